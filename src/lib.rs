@@ -0,0 +1,104 @@
+use std::sync::{Arc, Weak};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::track::track_remote::TrackRemote;
+use flume::Sender;
+use sfu::auth::Permissions;
+use sfu::media::{PeerKind, TrackSource};
+use sfu::recording::SenderClock;
+use sfu::simulcast::Layer;
+use sfu::transport::SignalTx;
+use std::collections::HashMap;
+
+pub mod sfu;
+
+pub use sfu::config::Config;
+pub use sfu::egress::RtpEgress;
+pub use sfu::ingest::{RtpIngest, RtpInput};
+pub use sfu::playback::Playback;
+pub use sfu::server::{SfuEvent, SfuHandle, SfuServer, SfuServerBuilder, Shutdown};
+pub use sfu::transport::{ChannelTransport, Transport};
+
+// Commands handled by the router task, which owns every room and peer connection
+#[derive(Debug, Clone)]
+pub enum PeerChanCommand {
+    SendIceCandidate {
+        uuid: String,
+        candidate: RTCIceCandidateInit,
+    },
+    ReceiveIceCandidate {
+        uuid: String,
+        candidate: RTCIceCandidateInit,
+    },
+    // Called when renegotiation needs to happen
+    SendOffer {
+        uuid: String
+    },
+    ReceiveOffer {
+        uuid: String,
+        room: String,
+        name: String,
+        sdp: RTCSessionDescription,
+        sources: HashMap<String, TrackSource>,
+        tx: SignalTx,
+        kind: PeerKind,
+        permissions: Permissions
+    },
+    ReceiveAnswer {
+        uuid: String,
+        sdp: RTCSessionDescription,
+    },
+    OnTrack {
+        uuid: String,
+        track: Arc<TrackRemote>,
+        // Kept up to date with the track's sender reports
+        clock: Arc<SenderClock>,
+    },
+    // A subscriber picking which simulcast layer of a publisher's track it receives
+    SelectLayer {
+        uuid: String,
+        publisher: String,
+        track_id: String,
+        layer: Layer
+    },
+    // Disconnect everyone in a room
+    CloseRoom {
+        room: String
+    },
+    // Record every track published in a room until told to stop
+    StartRecording {
+        room: String
+    },
+    StopRecording {
+        room: String
+    },
+    // Send a published track on as plain RTP, replying with the SDP describing it
+    StartEgress {
+        id: String,
+        egress: RtpEgress,
+        done: Sender<Result<String, String>>
+    },
+    StopEgress {
+        id: String
+    },
+    // Tell every peer the server is shutting down and turn new ones away. Done is signalled once
+    // the last peer has left.
+    Drain {
+        done: Sender<()>
+    },
+    // Disconnect every peer, signalling done once their connections are closed
+    CloseAll {
+        done: Sender<()>
+    },
+    // Called when the socket for a peer goes away, or the peer is removed
+    PeerLeft {
+        uuid: String
+    },
+    // Called when a peer's connection fails or closes. It names the connection, since by the time
+    // this is handled the peer may have left and joined again on a new one.
+    ConnectionClosed {
+        uuid: String,
+        pc: Weak<RTCPeerConnection>
+    }
+}
@@ -1,3 +1,18 @@
 pub mod signal; 
 pub mod api; 
 pub mod media; 
+pub mod whip; 
+pub mod whep; 
+pub mod simulcast; 
+pub mod config; 
+pub mod server; 
+pub mod transport; 
+pub mod metrics; 
+pub mod health; 
+pub mod auth; 
+pub mod recording; 
+pub mod webm; 
+pub mod playback; 
+pub mod local; 
+pub mod ingest; 
+pub mod egress; 
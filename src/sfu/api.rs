@@ -1,31 +1,43 @@
 use anyhow::Result;
-use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
-use std::sync::Arc;
-use tokio::time::Duration;
 use webrtc::api::API;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_VP8, MIME_TYPE_OPUS};
 use webrtc::api::APIBuilder;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
-use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::peer_connection::configuration::RTCConfiguration;
-use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
-use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
-use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
-use webrtc::rtp_transceiver::rtp_codec::{RTPCodecType, RTCRtpCodecCapability, RTCRtpCodecParameters};
-use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
-use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
-use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
-use webrtc::track::track_remote::TrackRemote;
-use webrtc::Error;
-use flume::Sender;
-use std::collections::HashMap;
-
-
-pub fn prepare_api() -> Result<API, anyhow::Error> {
-    let audio = true;
-    let video = true;
+use webrtc::rtp_transceiver::rtp_codec::{RTPCodecType, RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability};
+use crate::sfu::config::{Codec, Config};
+
+
+// The API for peer connections with clients, on the configured ports and addresses
+pub fn prepare_api(config: &Config) -> Result<API, anyhow::Error> {
+    // Control which ports and addresses peer connections use, for running behind firewalls and NAT
+    let mut s = SettingEngine::default();
+
+    if let Some((port_min, port_max)) = config.udp_port_range() {
+        s.set_ephemeral_udp_port_range(port_min, port_max)?;
+    }
+
+    if !config.nat_1to1_ips.is_empty() {
+        s.set_nat_1to1_ips(config.nat_1to1_ips.clone(), RTCIceCandidateType::Host);
+    }
+
+    build_api(config, s)
+}
+
+// The API for peer connections with publishers inside the process, like file playback. They
+// reach the SFU at the host's own addresses, which the NAT mapping would replace with public
+// ones, so they're left on the default settings.
+pub fn prepare_local_api(config: &Config) -> Result<API, anyhow::Error> {
+    build_api(config, SettingEngine::default())
+}
+
+fn build_api(config: &Config, s: SettingEngine) -> Result<API, anyhow::Error> {
+    let audio = config.has_codec(Codec::Opus);
+    let video = config.has_codec(Codec::Vp8);
 
     // Create a MediaEngine object to configure the supported codec
     let mut m = MediaEngine::default();
@@ -61,6 +73,21 @@ pub fn prepare_api() -> Result<API, anyhow::Error> {
             },
             RTPCodecType::Video,
         )?;
+
+        // Simulcast publishers identify each encoding by its rid, carried in these extensions
+        for extension in [
+            "urn:ietf:params:rtp-hdrext:sdes:mid",
+            "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id",
+            "urn:ietf:params:rtp-hdrext:sdes:repaired-rtp-stream-id",
+        ] {
+            m.register_header_extension(
+                RTCRtpHeaderExtensionCapability {
+                    uri: extension.to_owned(),
+                },
+                RTPCodecType::Video,
+                vec![],
+            )?;
+        }
     }
 
     // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
@@ -76,20 +103,26 @@ pub fn prepare_api() -> Result<API, anyhow::Error> {
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
+        .with_setting_engine(s)
         .build();
 
     Ok(api)
 }
 
-pub fn prepare_configuration() -> Result<RTCConfiguration, anyhow::Error> {
+pub fn prepare_configuration(config: &Config) -> Result<RTCConfiguration, anyhow::Error> {
     // Prepare the configuration
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
-        }],
+    let rtc_config = RTCConfiguration {
+        ice_servers: config.ice_servers
+            .iter()
+            .map(|s| RTCIceServer {
+                urls: s.urls.clone(),
+                username: s.username.clone(),
+                credential: s.credential.clone(),
+                ..Default::default()
+            })
+            .collect(),
         ..Default::default()
     };
 
-    Ok(config)
+    Ok(rtc_config)
 }
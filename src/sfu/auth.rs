@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Request};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use crate::sfu::config::Config;
+
+// What a token lets its holder do in its room
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Publish,
+    Subscribe,
+    // Join any room, and close rooms
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub publish: bool,
+    pub subscribe: bool,
+    pub admin: bool,
+}
+
+impl Permissions {
+    // For peers the embedding service joins through its handle
+    pub fn all() -> Self {
+        Permissions {
+            publish: true,
+            subscribe: true,
+            admin: true,
+        }
+    }
+
+    // For clients when authentication is turned off. They can publish and subscribe, but closing
+    // and recording rooms needs a token, or the handle.
+    pub fn anonymous() -> Self {
+        Permissions {
+            admin: false,
+            ..Permissions::all()
+        }
+    }
+
+    pub fn can_join(&self, claims: &Claims, room: &str) -> bool {
+        self.admin || claims.room == room
+    }
+}
+
+// The claims of a token issued by the application the SFU serves
+#[derive(Deserialize, Debug, Clone)]
+pub struct Claims {
+    // Who the holder is, as the issuing application knows them
+    pub sub: String,
+    pub room: String,
+    // The display name shown to the rest of the room, the subject if left out
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    pub exp: usize,
+}
+
+impl Claims {
+    pub fn permissions(&self) -> Permissions {
+        Permissions {
+            publish: self.permissions.contains(&Permission::Publish),
+            subscribe: self.permissions.contains(&Permission::Subscribe),
+            admin: self.permissions.contains(&Permission::Admin),
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        if self.name.is_empty() { &self.sub } else { &self.name }
+    }
+}
+
+// Checks the JWTs clients present, signed with either a shared HS256 secret or an ES256 key.
+pub struct Authenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl std::fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authenticator").field("algorithms", &self.validation.algorithms).finish()
+    }
+}
+
+impl Authenticator {
+    // None when no key is configured, in which case anyone may connect
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let (key, algorithm) = match (&config.jwt_secret, &config.jwt_public_key) {
+            (None, None) => return Ok(None),
+            (Some(secret), None) => (DecodingKey::from_secret(secret.as_bytes()), Algorithm::HS256),
+            (None, Some(path)) => {
+                let pem = std::fs::read(path)
+                    .map_err(|e| anyhow!("couldn't read JWT public key {}: {}", path.display(), e))?;
+                (DecodingKey::from_ec_pem(&pem)?, Algorithm::ES256)
+            }
+            (Some(_), Some(_)) => return Err(anyhow!("only one of a JWT secret or public key can be set")),
+        };
+
+        Ok(Some(Authenticator {
+            key,
+            validation: Validation::new(algorithm),
+        }))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        Ok(decode::<Claims>(token, &self.key, &self.validation)?.claims)
+    }
+
+    // Verify the token on a request, from a bearer Authorization header or, since browsers can't
+    // set headers on websockets, a `token` query parameter.
+    pub fn authenticate(&self, request: &Request<Body>) -> Result<Claims> {
+        let header = request.headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        let query = request.uri()
+            .query()
+            .and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("token=")));
+
+        match header.or(query) {
+            Some(token) => self.verify(token),
+            None => Err(anyhow!("missing token")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &str = "hunter2";
+
+    fn authenticator() -> Authenticator {
+        let config = Config { jwt_secret: Some(SECRET.to_owned()), ..Config::default() };
+        Authenticator::from_config(&config).unwrap().unwrap()
+    }
+
+    fn token(room: &str, permissions: &[&str]) -> String {
+        let claims = json!({
+            "sub": "alice",
+            "room": room,
+            "permissions": permissions,
+            // Far enough out to never expire
+            "exp": 4_000_000_000u64,
+        });
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    fn request(uri: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn token_from_bearer_header() {
+        let token = token("lobby", &["publish"]);
+        let claims = authenticator()
+            .authenticate(&request("/whip/lobby", Some(&format!("Bearer {}", token))))
+            .unwrap();
+
+        assert_eq!(claims.room, "lobby");
+        assert_eq!(claims.display_name(), "alice");
+        assert_eq!(claims.permissions(), Permissions { publish: true, subscribe: false, admin: false });
+    }
+
+    #[test]
+    fn token_from_query() {
+        let token = token("lobby", &["subscribe"]);
+        let claims = authenticator()
+            .authenticate(&request(&format!("/?room=lobby&token={}", token), None))
+            .unwrap();
+
+        assert_eq!(claims.permissions(), Permissions { publish: false, subscribe: true, admin: false });
+    }
+
+    #[test]
+    fn rejects_missing_or_forged_tokens() {
+        let auth = authenticator();
+        assert!(auth.authenticate(&request("/", None)).is_err());
+        assert!(auth.authenticate(&request("/", Some("Bearer not-a-token"))).is_err());
+
+        let forged = encode(
+            &Header::default(),
+            &json!({ "sub": "mallory", "room": "lobby", "exp": 4_000_000_000u64 }),
+            &EncodingKey::from_secret(b"guessed"),
+        ).unwrap();
+        assert!(auth.authenticate(&request(&format!("/?token={}", forged), None)).is_err());
+    }
+
+    #[test]
+    fn join_own_room_unless_admin() {
+        let auth = authenticator();
+        let member = auth.verify(&token("lobby", &["publish", "subscribe"])).unwrap();
+        assert!(member.permissions().can_join(&member, "lobby"));
+        assert!(!member.permissions().can_join(&member, "backstage"));
+
+        let admin = auth.verify(&token("lobby", &["admin"])).unwrap();
+        assert!(admin.permissions().can_join(&admin, "backstage"));
+    }
+}
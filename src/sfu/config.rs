@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::sfu::ingest::RtpIngest;
+use crate::sfu::recording::RecordingFormat;
+
+// Server settings. Each one comes from, in increasing order of precedence: the defaults below,
+// a TOML or JSON config file, environment variables, and command line flags.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    // Address the HTTP and websocket server listens on
+    pub listen: SocketAddr,
+    pub ice_servers: Vec<IceServer>,
+    // Codecs offered to peers, at least one of which must be enabled
+    pub codecs: Vec<Codec>,
+    // An env_logger filter, like "info" or "sfu=debug,webrtc=warn"
+    pub log_level: String,
+    // Bounds of the UDP ports peer connections bind, rather than any ephemeral port
+    pub udp_port_min: Option<u16>,
+    pub udp_port_max: Option<u16>,
+    // Public IPs announced in place of the host's own in host candidates, for servers behind a
+    // 1:1 NAT
+    pub nat_1to1_ips: Vec<String>,
+    // How long the server takes at most to exit after a SIGTERM. Peers get all but the last
+    // few seconds to leave on their own, then the rest are disconnected.
+    pub drain_timeout_secs: u64,
+    // Key for the JWTs clients must present, either an HS256 secret or the path of an ES256
+    // public key in PEM. Anyone can connect when neither is set.
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key: Option<PathBuf>,
+    // Where room recordings are written
+    pub recording_dir: PathBuf,
+    pub recording_format: RecordingFormat,
+    // Plain RTP sources to publish into rooms once the server is up
+    pub rtp_ingest: Vec<RtpIngest>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    // Only needed for TURN servers
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub credential: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Opus,
+    Vp8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::from(([127, 0, 0, 1], 8081)),
+            ice_servers: vec![IceServer {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                username: String::new(),
+                credential: String::new(),
+            }],
+            codecs: vec![Codec::Opus, Codec::Vp8],
+            log_level: "info".to_owned(),
+            udp_port_min: None,
+            udp_port_max: None,
+            nat_1to1_ips: vec![],
+            drain_timeout_secs: 30,
+            jwt_secret: None,
+            jwt_public_key: None,
+            recording_dir: PathBuf::from("recordings"),
+            recording_format: RecordingFormat::Webm,
+            rtp_ingest: vec![],
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(about = "A WebRTC selective forwarding unit")]
+struct Cli {
+    /// Path to a TOML or JSON config file
+    #[clap(long, env = "SFU_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, like 0.0.0.0:8081
+    #[clap(long, env = "SFU_LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// STUN or TURN server url, replacing any from the config file. Can be repeated.
+    #[clap(long = "ice-server", env = "SFU_ICE_SERVERS", use_value_delimiter = true)]
+    ice_servers: Vec<String>,
+
+    /// Username for the TURN servers given with --ice-server
+    #[clap(long, env = "SFU_ICE_USERNAME")]
+    ice_username: Option<String>,
+
+    /// Credential for the TURN servers given with --ice-server
+    #[clap(long, env = "SFU_ICE_CREDENTIAL")]
+    ice_credential: Option<String>,
+
+    /// Codec to offer, one of opus or vp8. Can be repeated.
+    #[clap(long = "codec", env = "SFU_CODECS", use_value_delimiter = true)]
+    codecs: Vec<String>,
+
+    /// Log filter, like info or sfu=debug,webrtc=warn
+    #[clap(long, env = "SFU_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// Lowest UDP port peer connections may bind
+    #[clap(long, env = "SFU_UDP_PORT_MIN")]
+    udp_port_min: Option<u16>,
+
+    /// Highest UDP port peer connections may bind
+    #[clap(long, env = "SFU_UDP_PORT_MAX")]
+    udp_port_max: Option<u16>,
+
+    /// Public IP to announce in host candidates when behind a 1:1 NAT. Can be repeated.
+    #[clap(long = "nat-1to1-ip", env = "SFU_NAT_1TO1_IPS", use_value_delimiter = true)]
+    nat_1to1_ips: Vec<String>,
+
+    /// Seconds the server takes at most to exit after a SIGTERM, most of it for peers to leave
+    #[clap(long, env = "SFU_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: Option<u64>,
+
+    /// Shared secret for HS256 signed client tokens
+    #[clap(long, env = "SFU_JWT_SECRET")]
+    jwt_secret: Option<String>,
+
+    /// Path to the PEM public key for ES256 signed client tokens
+    #[clap(long, env = "SFU_JWT_PUBLIC_KEY")]
+    jwt_public_key: Option<PathBuf>,
+
+    /// Directory room recordings are written to
+    #[clap(long, env = "SFU_RECORDING_DIR")]
+    recording_dir: Option<PathBuf>,
+
+    /// Recording format, webm for a file per participant or raw for IVF and Ogg per track
+    #[clap(long, env = "SFU_RECORDING_FORMAT")]
+    recording_format: Option<String>,
+}
+
+impl Config {
+    // Build the config from the command line, the environment and the config file they point to.
+    pub fn load() -> Result<Self> {
+        let cli = Cli::parse();
+
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(listen) = cli.listen {
+            config.listen = listen;
+        }
+
+        if !cli.ice_servers.is_empty() {
+            config.ice_servers = vec![IceServer {
+                urls: cli.ice_servers,
+                username: cli.ice_username.unwrap_or_default(),
+                credential: cli.ice_credential.unwrap_or_default(),
+            }];
+        }
+
+        if !cli.codecs.is_empty() {
+            config.codecs = cli.codecs
+                .iter()
+                .map(|c| Codec::parse(c))
+                .collect::<Result<Vec<Codec>>>()?;
+        }
+
+        if let Some(log_level) = cli.log_level {
+            config.log_level = log_level;
+        }
+
+        if cli.udp_port_min.is_some() {
+            config.udp_port_min = cli.udp_port_min;
+        }
+
+        if cli.udp_port_max.is_some() {
+            config.udp_port_max = cli.udp_port_max;
+        }
+
+        if !cli.nat_1to1_ips.is_empty() {
+            config.nat_1to1_ips = cli.nat_1to1_ips;
+        }
+
+        if let Some(drain_timeout_secs) = cli.drain_timeout_secs {
+            config.drain_timeout_secs = drain_timeout_secs;
+        }
+
+        if cli.jwt_secret.is_some() {
+            config.jwt_secret = cli.jwt_secret;
+        }
+
+        if cli.jwt_public_key.is_some() {
+            config.jwt_public_key = cli.jwt_public_key;
+        }
+
+        if let Some(recording_dir) = cli.recording_dir {
+            config.recording_dir = recording_dir;
+        }
+
+        if let Some(recording_format) = cli.recording_format {
+            config.recording_format = RecordingFormat::parse(&recording_format)?;
+        }
+
+        if config.codecs.is_empty() {
+            return Err(anyhow!("at least one codec must be enabled"));
+        }
+
+        if let Some((port_min, port_max)) = config.udp_port_range() {
+            if port_min > port_max {
+                return Err(anyhow!("udp_port_min {} is above udp_port_max {}", port_min, port_max));
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read config file {}: {}", path.display(), e))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Ok(serde_json::from_str(&contents)?),
+            Some("toml") => Ok(toml::from_str(&contents)?),
+            _ => Err(anyhow!("config file {} must be .toml or .json", path.display())),
+        }
+    }
+
+    pub fn has_codec(&self, codec: Codec) -> bool {
+        self.codecs.contains(&codec)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+
+    // The UDP port range, if either end of it is set
+    pub fn udp_port_range(&self) -> Option<(u16, u16)> {
+        match (self.udp_port_min, self.udp_port_max) {
+            (None, None) => None,
+            (min, max) => Some((min.unwrap_or(1024), max.unwrap_or(u16::MAX))),
+        }
+    }
+}
+
+impl Codec {
+    fn parse(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "opus" => Ok(Codec::Opus),
+            "vp8" => Ok(Codec::Vp8),
+            _ => Err(anyhow!("unsupported codec {:?}, expected opus or vp8", s)),
+        }
+    }
+}
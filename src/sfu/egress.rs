@@ -0,0 +1,143 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::UdpSocket;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::track::track_local::TrackLocalWriter;
+use webrtc::util::Marshal;
+
+// One published track sent on as plain RTP to a UDP destination, for consumers without WebRTC
+// like ffmpeg. The SDP describing the stream is what they open:
+//
+//   ffmpeg -protocol_whitelist file,udp,rtp -i egress.sdp -c copy out.webm
+//
+// Nothing comes back from the consumer, so video starts at the keyframe requested when the
+// egress starts. Besides SfuHandle::start_egress, clients with the admin permission can start
+// one with a start_egress message on the websocket.
+#[derive(Debug, Clone)]
+pub struct RtpEgress {
+    pub room: String,
+    pub publisher: String,
+    pub track_id: String,
+    // Where to send the RTP, like 127.0.0.1:5004
+    pub dest: SocketAddr,
+    // Any dynamic payload type, 96 to 127
+    pub payload_type: u8,
+    // Also write the SDP here, for consumers that read it from a file
+    pub sdp_path: Option<PathBuf>,
+}
+
+// Sends a forwarder's packets to a UDP destination in place of an output track, stamping them
+// with the egress's own SSRC and payload type.
+#[derive(Debug)]
+pub struct UdpSink {
+    socket: UdpSocket,
+    ssrc: u32,
+    payload_type: u8,
+}
+
+impl UdpSink {
+    pub async fn connect(dest: SocketAddr, payload_type: u8) -> Result<Self> {
+        let bind: SocketAddr = if dest.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(dest).await?;
+
+        Ok(UdpSink {
+            socket,
+            ssrc: rand::random(),
+            payload_type,
+        })
+    }
+}
+
+#[async_trait]
+impl TrackLocalWriter for UdpSink {
+    async fn write_rtp(&self, p: &Packet) -> webrtc::error::Result<usize> {
+        let mut packet = p.clone();
+        packet.header.ssrc = self.ssrc;
+        packet.header.payload_type = self.payload_type;
+
+        let bytes = packet.marshal()?;
+        self.write(&bytes).await
+    }
+
+    async fn write(&self, b: &[u8]) -> webrtc::error::Result<usize> {
+        self.socket
+            .send(b)
+            .await
+            .map_err(|e| webrtc::Error::new(format!("sending RTP to {:?}: {}", self.socket.peer_addr(), e)))
+    }
+}
+
+// The SDP a consumer needs to receive an egress
+pub fn sdp(egress: &RtpEgress, kind: RTPCodecType) -> String {
+    let ip_version = if egress.dest.is_ipv4() { "IP4" } else { "IP6" };
+    // Both payload formats fix the clock rate, and Opus is always described as stereo
+    let (media, rtpmap) = if kind == RTPCodecType::Video {
+        ("video", "VP8/90000")
+    } else {
+        ("audio", "opus/48000/2")
+    };
+
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN {ip} {addr}\r\n\
+         s={room} {publisher} {track}\r\n\
+         c=IN {ip} {addr}\r\n\
+         t=0 0\r\n\
+         m={media} {port} RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} {rtpmap}\r\n\
+         a=recvonly\r\n",
+        ip = ip_version,
+        addr = egress.dest.ip(),
+        room = egress.room,
+        publisher = egress.publisher,
+        track = egress.track_id,
+        media = media,
+        port = egress.dest.port(),
+        pt = egress.payload_type,
+        rtpmap = rtpmap,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn egress(dest: &str, payload_type: u8) -> RtpEgress {
+        RtpEgress {
+            room: "lobby".to_owned(),
+            publisher: "alice".to_owned(),
+            track_id: "camera".to_owned(),
+            dest: dest.parse().unwrap(),
+            payload_type,
+            sdp_path: None,
+        }
+    }
+
+    #[test]
+    fn video_sdp() {
+        assert_eq!(
+            sdp(&egress("127.0.0.1:5004", 96), RTPCodecType::Video),
+            "v=0\r\n\
+             o=- 0 0 IN IP4 127.0.0.1\r\n\
+             s=lobby alice camera\r\n\
+             c=IN IP4 127.0.0.1\r\n\
+             t=0 0\r\n\
+             m=video 5004 RTP/AVP 96\r\n\
+             a=rtpmap:96 VP8/90000\r\n\
+             a=recvonly\r\n"
+        );
+    }
+
+    #[test]
+    fn audio_sdp_over_ipv6() {
+        let sdp = sdp(&egress("[::1]:5006", 111), RTPCodecType::Audio);
+        assert!(sdp.contains("o=- 0 0 IN IP6 ::1\r\n"));
+        assert!(sdp.contains("c=IN IP6 ::1\r\n"));
+        assert!(sdp.contains("m=audio 5006 RTP/AVP 111\r\n"));
+        assert!(sdp.contains("a=rtpmap:111 opus/48000/2\r\n"));
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::Result;
+use hyper::{Body, Response, StatusCode};
+use crate::sfu::server::Shutdown;
+use crate::sfu::whip::text;
+
+// Liveness, answered for as long as the process is serving HTTP
+pub const HEALTH_PATH: &str = "/healthz";
+
+// Readiness, which fails once shutdown starts so load balancers stop sending new clients
+pub const READY_PATH: &str = "/readyz";
+
+pub fn handle_health() -> Result<Response<Body>, anyhow::Error> {
+    text(StatusCode::OK, "ok".to_owned())
+}
+
+pub fn handle_ready(shutdown: &Shutdown) -> Result<Response<Body>, anyhow::Error> {
+    if shutdown.is_draining() {
+        return text(StatusCode::SERVICE_UNAVAILABLE, "shutting down".to_owned());
+    }
+
+    text(StatusCode::OK, "ok".to_owned())
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use crate::sfu::local::{opus_codec, vp8_codec, LocalPublisher};
+use crate::sfu::server::SfuHandle;
+
+// How often a quiet ingest checks whether it's been stopped
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Plain RTP received on local UDP ports and published into a room, for sources without WebRTC
+// like ffmpeg or GStreamer. Each stream needs a port of its own:
+//
+//   ffmpeg -re -i in.mp4 -an -c:v libvpx -g 50 -f rtp rtp://127.0.0.1:5004 \
+//     -vn -c:a libopus -f rtp rtp://127.0.0.1:5006
+//
+// The source can't be asked for keyframes, so video starts for new subscribers at its next one
+// and a short keyframe interval helps.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RtpIngest {
+    pub room: String,
+    // Shown to the room like any participant's name
+    #[serde(default)]
+    pub name: String,
+    // VP8 packets
+    pub video: Option<RtpInput>,
+    // Opus packets
+    pub audio: Option<RtpInput>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RtpInput {
+    // The local address to receive on, like 127.0.0.1:5004
+    pub addr: SocketAddr,
+    // Packets with any other payload type are dropped. ffmpeg sends 96 unless told otherwise.
+    #[serde(default = "default_payload_type")]
+    pub payload_type: u8,
+}
+
+fn default_payload_type() -> u8 {
+    96
+}
+
+// Bind the ingest's ports and join it to its room as a publisher. It stays until the handle's
+// leave removes it, or the server shuts down.
+pub async fn start(handle: &SfuHandle, uuid: &str, ingest: RtpIngest) -> Result<()> {
+    let mut inputs = vec![];
+    for (input, codec, id) in [(&ingest.video, vp8_codec(), "video"), (&ingest.audio, opus_codec(), "audio")] {
+        if let Some(input) = input {
+            let socket = UdpSocket::bind(input.addr)
+                .await
+                .map_err(|e| anyhow!("couldn't bind RTP ingest to {}: {}", input.addr, e))?;
+            let track = Arc::new(TrackLocalStaticRTP::new(codec, id.to_owned(), uuid.to_owned()));
+            inputs.push((socket, track, input.payload_type));
+        }
+    }
+    if inputs.is_empty() {
+        return Err(anyhow!("nothing to ingest"));
+    }
+
+    let publisher = Arc::new(LocalPublisher::new(handle, uuid, &ingest.room).await?);
+    for (_, track, _) in &inputs {
+        publisher.add_track(Arc::clone(track) as Arc<dyn TrackLocal + Send + Sync>).await?;
+    }
+    publisher.join(&ingest.name).await?;
+
+    for (socket, track, payload_type) in inputs {
+        log::info!("📥 Ingesting RTP from {} into room {} as {}", socket.local_addr()?, ingest.room, uuid);
+        let publisher = Arc::clone(&publisher);
+        tokio::spawn(async move {
+            if let Err(err) = forward_rtp(&socket, &track, payload_type, &publisher).await {
+                log::warn!("RTP ingest on {:?} failed: {}", socket.local_addr(), err);
+            }
+            publisher.leave().await;
+        });
+    }
+
+    Ok(())
+}
+
+// Write every packet of the expected payload type to the track, which rewrites the SSRC and
+// payload type for the SFU's side of the connection.
+async fn forward_rtp(socket: &UdpSocket, track: &TrackLocalStaticRTP, payload_type: u8, publisher: &LocalPublisher) -> Result<()> {
+    let mut buf = vec![0u8; 1500];
+
+    while !publisher.is_stopped() {
+        let n = match tokio::time::timeout(POLL_INTERVAL, socket.recv(&mut buf)).await {
+            Ok(result) => result?,
+            Err(_) => continue,
+        };
+
+        // RTP version 2, and at least a fixed header
+        if n < 12 || buf[0] >> 6 != 2 || buf[1] & 0x7f != payload_type {
+            continue;
+        }
+
+        if let Err(err) = track.write(&buf[..n]).await {
+            log::warn!("Failed to forward ingested RTP: {}", err);
+        }
+    }
+
+    Ok(())
+}
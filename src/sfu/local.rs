@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use flume::Receiver;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS, MIME_TYPE_VP8};
+use webrtc::api::APIBuilder;
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::TrackLocal;
+use crate::sfu::media::PeerKind;
+use crate::sfu::server::SfuHandle;
+use crate::sfu::signal::SocketMessage;
+
+// How long the SFU gets to answer and connect before the publisher is given up on
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A publisher running inside the SFU's process, like file playback or an RTP ingest. It
+// publishes through a peer connection of its own, the way a WHIP client would, so its tracks
+// reach subscribers, recordings and events like anyone else's.
+pub struct LocalPublisher {
+    pub uuid: String,
+    pub room: String,
+    handle: SfuHandle,
+    pc: Arc<RTCPeerConnection>,
+    // Set once the connection to the SFU closes, or the server shuts down
+    stopped: Arc<AtomicBool>,
+}
+
+impl LocalPublisher {
+    pub async fn new(handle: &SfuHandle, uuid: &str, room: &str) -> Result<Self> {
+        // Independent of the SFU's own UDP settings, which may have its ports bound already
+        let mut m = MediaEngine::default();
+        m.register_default_codecs()?;
+
+        let mut registry = Registry::new();
+        registry = register_default_interceptors(registry, &mut m)?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .build();
+
+        Ok(LocalPublisher {
+            uuid: uuid.to_owned(),
+            room: room.to_owned(),
+            handle: handle.clone(),
+            pc: Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Add a track to publish. Every track has to be added before joining.
+    pub async fn add_track(&self, track: Arc<dyn TrackLocal + Send + Sync>) -> Result<()> {
+        let rtp_sender = self.pc.add_track(track).await?;
+
+        // The interceptors only process the SFU's RTCP if it's read. Keyframe requests are
+        // dropped, local publishers send keyframes when their source has them.
+        tokio::spawn(async move {
+            let mut rtcp_buf = vec![0u8; 1500];
+            while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+        });
+
+        Ok(())
+    }
+
+    // Join the room and wait for the connection to come up
+    pub async fn join(&self, name: &str) -> Result<()> {
+        // The SFU doesn't trickle to peers like this one, so every candidate goes in the offer
+        let offer = self.pc.create_offer(None).await?;
+        let mut gather_complete = self.pc.gathering_complete_promise().await;
+        self.pc.set_local_description(offer).await?;
+        let _ = gather_complete.recv().await;
+        let offer = self.pc.local_description().await.ok_or_else(|| anyhow!("no local description"))?;
+
+        let (state_tx, state_rx) = flume::unbounded::<RTCPeerConnectionState>();
+        let stopped = Arc::clone(&self.stopped);
+        self.pc.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+            if s == RTCPeerConnectionState::Failed || s == RTCPeerConnectionState::Closed {
+                stopped.store(true, Ordering::Relaxed);
+            }
+            let _ = state_tx.send(s);
+            Box::pin(async {})
+        })).await;
+
+        let rx = self.handle.join(&self.uuid, &self.room, name, PeerKind::Local, offer)?;
+        let result = match tokio::time::timeout(CONNECT_TIMEOUT, self.connect(&rx, state_rx)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out connecting")),
+        };
+        if let Err(err) = result {
+            self.leave().await;
+            return Err(err);
+        }
+
+        // Leave when the server starts draining, rather than holding up its shutdown
+        let handle = self.handle.clone();
+        let uuid = self.uuid.to_owned();
+        let stopped = Arc::clone(&self.stopped);
+        tokio::spawn(async move {
+            while let Ok(message) = rx.recv_async().await {
+                if let SocketMessage::Shutdown {} = message {
+                    stopped.store(true, Ordering::Relaxed);
+                    let _ = handle.leave(&uuid);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    // Take the SFU's answer and wait for the connection to come up
+    async fn connect(&self, rx: &Receiver<SocketMessage>, state_rx: Receiver<RTCPeerConnectionState>) -> Result<()> {
+        match rx.recv_async().await? {
+            SocketMessage::Answer { sdp, .. } => self.pc.set_remote_description(sdp).await?,
+            SocketMessage::Error { message } => return Err(anyhow!(message)),
+            other => return Err(anyhow!("expected an answer, got {:?}", other)),
+        }
+
+        while let Ok(state) = state_rx.recv_async().await {
+            match state {
+                RTCPeerConnectionState::Connected => return Ok(()),
+                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => break,
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("peer connection didn't connect"))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub async fn leave(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let _ = self.handle.leave(&self.uuid);
+        let _ = self.pc.close().await;
+    }
+}
+
+// The codecs local publishers send
+pub fn vp8_codec() -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_VP8.to_owned(),
+        clock_rate: 90000,
+        ..Default::default()
+    }
+}
+
+pub fn opus_codec() -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_OPUS.to_owned(),
+        clock_rate: 48000,
+        channels: 2,
+        ..Default::default()
+    }
+}
@@ -1,252 +1,1381 @@
-use anyhow::Result;
-use webrtc::api::media_engine::{MIME_TYPE_VP8, MIME_TYPE_OPUS};
+use anyhow::{anyhow, Result};
 use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use webrtc::api::API;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
 use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::sender_report::SenderReport;
 use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
 use webrtc::track::track_remote::TrackRemote;
 use flume::Sender;
 use flume::Receiver;
 use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use crate::sfu::auth::Permissions;
+use crate::sfu::config::Config;
+use crate::sfu::egress::{self, RtpEgress, UdpSink};
+use crate::sfu::metrics;
+use crate::sfu::recording::{Recorder, RecordingFormat, SenderClock, WebmRecording};
+use crate::sfu::server::SfuEvent;
 use crate::sfu::signal::SocketMessage;
+use crate::sfu::simulcast::{Forwarder, Layer};
+use crate::sfu::transport::SignalTx;
 use crate::PeerChanCommand;
 
+// Output tracks are keyed by the publisher's uuid and the id of the source track
+pub type TrackKey = (String, String);
+
+// How often subscribers are re-pointed at their preferred simulcast layer, and the window the
+// layers are ranked over
+const LAYER_RANKING_INTERVAL: Duration = Duration::from_secs(2);
+
+// Subscribers can't ask for a keyframe more often than this, however many of them ask
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+// Trickled candidates held for a peer whose remote description isn't set yet. Anything past
+// this is dropped, ICE can still connect on the candidates in the SDP.
+const MAX_PENDING_CANDIDATES: usize = 64;
+
+// Forwards subscribers' keyframe requests for a track on to its publisher as a PLI, collapsing
+// requests that arrive close together into one.
+#[derive(Debug)]
+pub struct KeyframeRequester {
+    pc: Weak<RTCPeerConnection>,
+    media_ssrc: u32,
+    last_sent: StdMutex<Option<Instant>>,
+}
+
+impl KeyframeRequester {
+    pub fn new(pc: &Arc<RTCPeerConnection>, media_ssrc: u32) -> Self {
+        KeyframeRequester {
+            pc: Arc::downgrade(pc),
+            media_ssrc,
+            last_sent: StdMutex::new(None),
+        }
+    }
+
+    pub async fn request(&self) -> Result<()> {
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            if last_sent.map_or(false, |t| t.elapsed() < KEYFRAME_REQUEST_INTERVAL) {
+                return Ok(());
+            }
+            *last_sent = Some(Instant::now());
+        }
+
+        if let Some(pc) = self.pc.upgrade() {
+            pc.write_rtcp(&[Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc: self.media_ssrc,
+            })]).await?;
+            metrics::PLIS_SENT.inc();
+        }
+
+        Ok(())
+    }
+}
+
+// One encoding of a published track. Tracks that aren't simulcast have a single layer, with an
+// empty rid.
+#[derive(Debug)]
+pub struct TrackLayer {
+    pub track: Arc<TrackRemote>,
+    pub keyframes: KeyframeRequester,
+    // Payload bytes received since the last ranking pass
+    pub bytes: AtomicU64,
+    // Payload bytes received over the ranking interval before that
+    pub recent_bytes: AtomicU64,
+    // Set once the publisher stops sending this layer
+    pub ended: AtomicBool,
+    pub clock: Arc<SenderClock>,
+}
+
+// A track received from a publisher, along with every output track it is forwarded to
+#[derive(Debug, Clone)]
+pub struct PublishedTrack {
+    pub id: String,
+    pub kind: RTPCodecType,
+    pub codec: RTCRtpCodecCapability,
+    // The encodings received for this track, keyed by rid
+    pub layers: Arc<RwLock<HashMap<String, Arc<TrackLayer>>>>,
+    pub source: TrackSource,
+    // The stream id of this track's output tracks on subscribers
+    pub stream_id: String,
+    // Forwarders feeding output tracks on subscribers, keyed by subscriber uuid
+    pub outputs: Arc<RwLock<HashMap<String, Arc<Mutex<Forwarder>>>>>,
+    // Set while the track's room is being recorded
+    pub recorder: Arc<StdMutex<Option<Recorder>>>,
+}
+
+impl PublishedTrack {
+    // The event telling subscribers about this track
+    pub fn published_event(&self, publisher_uuid: &str) -> SocketMessage {
+        SocketMessage::TrackPublished {
+            publisher: publisher_uuid.to_owned(),
+            track_id: self.id.to_owned(),
+            stream_id: self.stream_id.to_owned(),
+            kind: self.kind.to_string(),
+            source: self.source,
+        }
+    }
+
+    // The rids of this track's live layers, from lowest to highest quality by how much each has
+    // carried lately. Browsers pause their higher layers under congestion, and a paused layer
+    // drops out within two ranking intervals, so its subscribers fall back to another.
+    pub async fn ranked_layers(&self) -> Vec<String> {
+        let layers = self.layers.read().await;
+        let mut ranked: Vec<(&String, u64)> = layers
+            .iter()
+            .filter(|(_, layer)| !layer.ended.load(Ordering::Relaxed))
+            .map(|(rid, layer)| (rid, layer.recent_bytes.load(Ordering::Relaxed) + layer.bytes.load(Ordering::Relaxed)))
+            .filter(|(_, bytes)| *bytes > 0)
+            .collect();
+        ranked.sort_by_key(|(_, bytes)| *bytes);
+        ranked.into_iter().map(|(rid, _)| rid.to_owned()).collect()
+    }
+
+    pub async fn request_keyframe(&self, rid: &str) -> Result<()> {
+        if self.kind != RTPCodecType::Video {
+            return Ok(());
+        }
+
+        let layer = self.layers.read().await.get(rid).cloned();
+        if let Some(layer) = layer {
+            layer.keyframes.request().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn select_layer(&self, subscriber_uuid: &str, layer: Layer) -> Result<()> {
+        if let Some(forwarder) = self.outputs.read().await.get(subscriber_uuid) {
+            forwarder.lock().await.preferred = layer;
+        }
+
+        self.retarget().await
+    }
+
+    // Point every subscriber at the layer it prefers, as layers come and go and their ranking
+    // changes, asking for a keyframe on any layer that subscribers are switching to.
+    pub async fn retarget(&self) -> Result<()> {
+        let ranked = self.ranked_layers().await;
+        let forwarders: Vec<Arc<Mutex<Forwarder>>> = self.outputs.read().await.values().cloned().collect();
+
+        let mut switching: Vec<String> = vec![];
+        for forwarder in forwarders {
+            let mut forwarder = forwarder.lock().await;
+            if let Some(rid) = forwarder.preferred.pick(&ranked) {
+                forwarder.target = rid.to_owned();
+            }
+            if forwarder.is_switching() && !switching.contains(&forwarder.target) {
+                switching.push(forwarder.target.to_owned());
+            }
+        }
+
+        for rid in switching {
+            self.request_keyframe(&rid).await?;
+        }
+
+        Ok(())
+    }
+}
+
+// An output track on a subscriber, with the sender that carries it on the subscriber's connection
+#[derive(Debug, Clone)]
+pub struct OutputTrack {
+    pub track: Arc<TrackLocalStaticRTP>,
+    pub sender: Arc<RTCRtpSender>,
+}
+
+// How a peer takes part in its room
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerKind {
+    // A websocket client that publishes its media and subscribes to everyone else's
+    Participant,
+    // A WHIP client that only publishes, and doesn't trickle or renegotiate
+    Ingest,
+    // A publisher inside the SFU's process, like file playback or an RTP ingest. It's an Ingest
+    // that connects over the host's own addresses, so it's left out of the NAT mapping.
+    Local,
+    // A WHEP client that only watches, either one publisher or the whole room. It can't be
+    // renegotiated with, so it only receives the tracks published when it joined.
+    Viewer {
+        publisher: Option<String>,
+    },
+}
+
+impl PeerKind {
+    // Whether the given publisher's tracks should be forwarded to this peer
+    pub fn subscribes_to(&self, publisher_uuid: &str) -> bool {
+        match self {
+            PeerKind::Participant => true,
+            PeerKind::Ingest | PeerKind::Local => false,
+            PeerKind::Viewer { publisher } => publisher.as_deref().map_or(true, |p| p == publisher_uuid),
+        }
+    }
+
+    // Whether the peer has a live signaling channel for trickled candidates and renegotiation.
+    // Otherwise candidates are gathered into the answer and the session is fixed after it.
+    pub fn signals(&self) -> bool {
+        *self == PeerKind::Participant
+    }
+}
+
+// What a published track carries, as announced by its publisher
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackSource {
+    Camera,
+    Microphone,
+    Screen,
+}
+
+impl TrackSource {
+    // The source assumed for tracks their publisher didn't describe
+    pub fn default_for(kind: RTPCodecType) -> Self {
+        if kind == RTPCodecType::Audio {
+            TrackSource::Microphone
+        } else {
+            TrackSource::Camera
+        }
+    }
+
+    // The stream id output tracks from this source are grouped under on subscribers. A
+    // publisher's camera and microphone share a stream so they're played in sync, while a screen
+    // share gets its own.
+    pub fn stream_id(&self, publisher_uuid: &str) -> String {
+        match self {
+            TrackSource::Screen => format!("{}-screen", publisher_uuid),
+            _ => publisher_uuid.to_owned(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Peer {
     // The peer connection itself
     pub pc: Arc<RTCPeerConnection>,
     // Copy of the socket to transmit back on
-    pub tx: Sender<SocketMessage>,
-    pub output_tracks: HashMap<String, Arc<TrackLocalStaticRTP>>,
+    pub tx: SignalTx,
+    // One output track per (publisher uuid, source track id) that this peer subscribes to
+    pub output_tracks: HashMap<TrackKey, OutputTrack>,
+    // Tracks this peer is publishing, keyed by source track id
+    pub published_tracks: HashMap<String, PublishedTrack>,
     // The id for this peer in the call
     pub uuid: String,
+    // The room this peer joined
+    pub room: String,
+    pub kind: PeerKind,
+    // What the peer's token allows it to do
+    pub permissions: Permissions,
+    // The display name shown to the rest of the room
+    pub name: String,
+    // What each of this peer's tracks carries, keyed by source track id
+    pub track_sources: HashMap<String, TrackSource>,
+    pub negotiation: Negotiation,
+    // The WebM file this peer's camera and microphone are being recorded to
+    pub recording: Option<Arc<StdMutex<WebmRecording>>>,
+}
+
+// Where a peer is in renegotiating its connection. Either side can offer, so when both do at
+// once the SFU plays the impolite peer of perfect negotiation: it keeps its own offer and ignores
+// the client's, and the client rolls its offer back, answers ours and offers again afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct Negotiation {
+    // An offer from the SFU is waiting on the client's answer
+    pub offer_outstanding: bool,
+    // Renegotiation was needed while an offer was outstanding, so another goes out once it's
+    // answered
+    pub renegotiate: bool,
+}
+
+impl Peer {
+    // The event telling the rest of the room about this peer
+    pub fn joined_event(&self) -> SocketMessage {
+        SocketMessage::ParticipantJoined {
+            uuid: self.uuid.to_owned(),
+            name: self.name.to_owned(),
+        }
+    }
+}
+
+// A call, isolated from every other room on the server
+#[derive(Debug, Default)]
+pub struct Room {
+    pub name: String,
+    pub peers: HashMap<String, Peer>,
+    // Whether every track published in the room is being recorded
+    pub recording: bool,
+}
+
+// Every active room, along with an index of which room each peer is in. Rooms are created when
+// their first peer joins and destroyed when their last peer leaves.
+#[derive(Debug, Default)]
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+    peer_rooms: HashMap<String, String>,
 }
 
-// This is ran in a tokio task, that holds all the shared state. It's communicated to by channels.
-pub async fn handle_peer_connection_commands(peer_chan_rx: Receiver<PeerChanCommand>, peer_chan_tx: Sender<PeerChanCommand>) -> Result<()> {
-    let api = crate::sfu::api::prepare_api()?;
+impl Rooms {
+    pub fn peer(&self, uuid: &str) -> Option<&Peer> {
+        let room = self.peer_rooms.get(uuid)?;
+        self.rooms.get(room)?.peers.get(uuid)
+    }
+
+    pub fn peer_mut(&mut self, uuid: &str) -> Option<&mut Peer> {
+        let room = self.peer_rooms.get(uuid)?;
+        self.rooms.get_mut(room)?.peers.get_mut(uuid)
+    }
+
+    // The peers sharing a room with the given peer, including the peer itself
+    pub fn peers_of(&mut self, uuid: &str) -> Option<&mut HashMap<String, Peer>> {
+        let room = self.peer_rooms.get(uuid)?;
+        self.rooms.get_mut(room).map(|r| &mut r.peers)
+    }
+
+    // Every peer in every room
+    pub fn uuids(&self) -> Vec<String> {
+        self.peer_rooms.keys().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peer_rooms.is_empty()
+    }
+
+    pub fn room(&self, room: &str) -> Option<&Room> {
+        self.rooms.get(room)
+    }
+
+    pub fn room_mut(&mut self, room: &str) -> Option<&mut Room> {
+        self.rooms.get_mut(room)
+    }
+
+    pub fn peers_of_room(&self, room: &str) -> Option<&HashMap<String, Peer>> {
+        self.rooms.get(room).map(|r| &r.peers)
+    }
 
-    let mut peers: HashMap<String, Peer> = HashMap::new();
-    // let mut output_tracks: HashMap<String, Arc<TrackLocalStaticRTP>> = HashMap::new();
-    // let mut input_tracks: HashMap<String, Arc<TrackRemote>> = HashMap::new();
+    // The peers in a room, creating the room if this is the first peer to join
+    pub fn join(&mut self, room: &str) -> &mut HashMap<String, Peer> {
+        &mut self.rooms
+            .entry(room.to_owned())
+            .or_insert_with(|| {
+                log::info!("🏠 Creating room {}", room);
+                metrics::ROOMS.inc();
+                Room { name: room.to_owned(), peers: HashMap::new(), recording: false }
+            })
+            .peers
+    }
 
-    while let Ok(cmd) = peer_chan_rx.recv_async().await {
+    pub fn insert(&mut self, peer: Peer) {
+        self.peer_rooms.insert(peer.uuid.to_owned(), peer.room.to_owned());
+        self.join(&peer.room.to_owned()).insert(peer.uuid.to_owned(), peer);
+        metrics::PEERS.inc();
+    }
+
+    // Remove a peer from its room, returning it along with the peers left behind
+    pub fn leave(&mut self, uuid: &str) -> Option<(Peer, &mut HashMap<String, Peer>)> {
+        let room = self.peer_rooms.remove(uuid)?;
+        let peers = &mut self.rooms.get_mut(&room)?.peers;
+        let peer = peers.remove(uuid)?;
+        metrics::PEERS.dec();
+        Some((peer, peers))
+    }
+
+    // Destroy the room if nobody is left in it, returning whether it was
+    pub fn close_if_empty(&mut self, room: &str) -> bool {
+        if self.rooms.get(room).map_or(false, |r| r.peers.is_empty()) {
+            log::info!("🏚 Closing empty room {}", room);
+            self.rooms.remove(room);
+            metrics::ROOMS.dec();
+            return true;
+        }
+
+        false
+    }
+}
+
+// An error handling a command for one peer. It's logged and reported to that peer, and everyone
+// else carries on.
+#[derive(Debug)]
+pub struct PeerError {
+    pub uuid: String,
+    pub error: anyhow::Error,
+    // Set when the peer's connection is left in a state it can't recover from, so it's
+    // disconnected rather than left wedged
+    pub disconnect: bool,
+}
+
+impl PeerError {
+    pub fn new(uuid: &str, error: impl Into<anyhow::Error>) -> Self {
+        PeerError {
+            uuid: uuid.to_owned(),
+            error: error.into(),
+            disconnect: false,
+        }
+    }
+
+    pub fn unknown_peer(uuid: &str) -> Self {
+        PeerError::new(uuid, anyhow!("unknown peer {}", uuid))
+    }
+}
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer {}: {:#}", self.uuid, self.error)
+    }
+}
+
+// Attach the peer a command was for to its errors
+trait ForPeer<T> {
+    fn for_peer(self, uuid: &str) -> Result<T, PeerError>;
+
+    // For errors that leave the peer's connection unusable
+    fn or_disconnect(self, uuid: &str) -> Result<T, PeerError>;
+}
+
+impl<T, E: Into<anyhow::Error>> ForPeer<T> for Result<T, E> {
+    fn for_peer(self, uuid: &str) -> Result<T, PeerError> {
+        self.map_err(|e| PeerError::new(uuid, e))
+    }
+
+    fn or_disconnect(self, uuid: &str) -> Result<T, PeerError> {
+        self.map_err(|e| PeerError { disconnect: true, ..PeerError::new(uuid, e) })
+    }
+}
+
+// The state owned by the actor task. Only the actor touches it, so none of it is locked.
+pub struct Router {
+    api: API,
+    // For local publishers, without the UDP settings meant for remote clients
+    local_api: API,
+    config: Arc<Config>,
+    rooms: Rooms,
+    // Candidates that arrived before their peer's remote description, keyed by peer uuid
+    pending_candidates: HashMap<String, Vec<RTCIceCandidateInit>>,
+    // For registering callbacks on new peer connections, which feed commands back to the actor
+    peer_chan_tx: Sender<PeerChanCommand>,
+    events: broadcast::Sender<SfuEvent>,
+    // Set once the server starts shutting down, to be signalled when the last peer leaves
+    drained: Option<Sender<()>>,
+    // The publisher uuid and track id each RTP egress is sending, keyed by egress id
+    egresses: HashMap<String, (String, String)>,
+}
+
+impl Router {
+    pub async fn new(config: Arc<Config>, peer_chan_tx: Sender<PeerChanCommand>, events: broadcast::Sender<SfuEvent>) -> Result<Self> {
+        Ok(Router {
+            api: crate::sfu::api::prepare_api(&config)?,
+            local_api: crate::sfu::api::prepare_local_api(&config)?,
+            config,
+            rooms: Rooms::default(),
+            pending_candidates: HashMap::new(),
+            peer_chan_tx,
+            events,
+            drained: None,
+            egresses: HashMap::new(),
+        })
+    }
+
+    // This is ran in a tokio task, that holds all the shared state. It's communicated to by
+    // channels.
+    pub async fn run(mut self, peer_chan_rx: Receiver<PeerChanCommand>) {
+        while let Ok(cmd) = peer_chan_rx.recv_async().await {
+            if let Err(err) = self.handle(cmd).await {
+                self.report(err).await;
+            }
+        }
+    }
+
+    pub async fn handle(&mut self, cmd: PeerChanCommand) -> Result<(), PeerError> {
         use PeerChanCommand::*;
 
-        println!("👻👻👻👻");
         match cmd {
             SendIceCandidate { uuid, candidate } => {
-                let peer = peers.get(&uuid).unwrap();
-                let pc = Arc::clone(&peer.pc);
+                let peer = self.rooms.peer(&uuid).ok_or_else(|| PeerError::unknown_peer(&uuid))?;
+                if !peer.kind.signals() {
+                    return Ok(());
+                }
 
-                peer.tx.send(SocketMessage {
-                    event: String::from("candidate"),
-                    data: candidate,
-                    uuid: uuid.to_owned()
-                }).unwrap();
+                peer.tx.send(SocketMessage::Candidate {
+                    uuid: uuid.to_owned(),
+                    candidate
+                }).for_peer(&uuid)?;
             }
             ReceiveIceCandidate { uuid, candidate } => {
-                println!("\nReceived Ice candidate.\n");
-                thread::sleep(Duration::from_millis(200));
-                let peer = peers.get(&uuid).unwrap();
-                let pc = Arc::clone(&peer.pc);
-                let can: RTCIceCandidateInit = serde_json::from_str(&candidate).unwrap();
-                pc.add_ice_candidate(can).await.unwrap();
+                let pc = self.rooms.peer(&uuid).map(|p| Arc::clone(&p.pc));
+
+                // Candidates can only be added once the offer they belong to has been applied
+                match pc {
+                    Some(pc) if pc.remote_description().await.is_some() => {
+                        pc.add_ice_candidate(candidate).await.for_peer(&uuid)?;
+                    }
+                    _ => {
+                        let pending = self.pending_candidates.entry(uuid.to_owned()).or_default();
+                        if pending.len() >= MAX_PENDING_CANDIDATES {
+                            return Err(PeerError::new(&uuid, anyhow!("too many candidates before an offer")));
+                        }
+                        pending.push(candidate);
+                    }
+                }
             }
             SendOffer { uuid } => {
-                println!("👀 Renegotiating for {}...", uuid);
-                let peer = peers.get_mut(&uuid).unwrap();
+                let peer = self.rooms.peer_mut(&uuid).ok_or_else(|| PeerError::unknown_peer(&uuid))?;
+                if !peer.kind.signals() {
+                    return Ok(());
+                }
+
+                // Only one offer can be in flight, changes made meanwhile go out in the next one
+                if peer.negotiation.offer_outstanding {
+                    log::info!("⏳ Queueing renegotiation for {} until its answer arrives", uuid);
+                    peer.negotiation.renegotiate = true;
+                    return Ok(());
+                }
+
+                log::info!("👀 Renegotiating for {}...", uuid);
                 let pc = Arc::clone(&peer.pc);
 
-                let offer = pc.create_offer(None).await?;
-                let offer_string = serde_json::to_string(&offer)?;
+                let offer = pc.create_offer(None).await.or_disconnect(&uuid)?;
 
-                pc.set_local_description(offer).await.unwrap();
+                pc.set_local_description(offer.clone()).await.or_disconnect(&uuid)?;
+                peer.negotiation = Negotiation {
+                    offer_outstanding: true,
+                    renegotiate: false,
+                };
 
-                peer.tx.send(SocketMessage {
-                    event: String::from("offer"),
-                    data: offer_string,
-                    uuid: uuid.to_owned()
-                }).unwrap();
+                peer.tx.send(SocketMessage::Offer {
+                    uuid: uuid.to_owned(),
+                    sdp: offer,
+                    sources: HashMap::new()
+                }).for_peer(&uuid)?;
             }
-            ReceiveOffer { uuid, sdp, tx } => {
-                let tx_clone = tx.clone();
-                match peers.get(&uuid) {
+            ReceiveOffer { uuid, room, name, sdp, sources, tx, kind, permissions } => {
+                match self.rooms.peer_mut(&uuid) {
                     Some(peer) => {
+                        peer.track_sources.extend(sources);
+
+                        // Glare, the client will roll its offer back and answer ours instead
+                        if peer.negotiation.offer_outstanding {
+                            log::info!("💥 Ignoring offer from {} that collided with ours", uuid);
+                            return Ok(());
+                        }
+
                         let pc = Arc::clone(&peer.pc);
-                        let offer = RTCSessionDescription::offer(sdp).unwrap();
-                        pc.set_remote_description(offer).await.unwrap();
+                        let tx = Arc::clone(&peer.tx);
+
+                        pc.set_remote_description(sdp).await.or_disconnect(&uuid)?;
+                        self.add_pending_candidates(&uuid).await?;
+
+                        let answer = pc.create_answer(None).await.or_disconnect(&uuid)?;
+                        pc.set_local_description(answer.clone()).await.or_disconnect(&uuid)?;
+
+                        tx.send(SocketMessage::Answer {
+                            uuid: uuid.to_owned(),
+                            sdp: answer
+                        }).for_peer(&uuid)?;
                     }
                     None => {
-                        let config = crate::sfu::api::prepare_configuration()?;
+                        if self.drained.is_some() {
+                            let _ = tx.send(SocketMessage::error("server is shutting down"));
+                            return Ok(());
+                        }
+
+                        // Local publishers need no ICE servers to reach us
+                        let (api, rtc_config) = if kind == PeerKind::Local {
+                            (&self.local_api, RTCConfiguration::default())
+                        } else {
+                            (&self.api, crate::sfu::api::prepare_configuration(&self.config).for_peer(&uuid)?)
+                        };
 
-                        // Step 1: Make a output_track for every other peer
-                        let mut peer = Peer {
-                            pc: Arc::new(api.new_peer_connection(config).await?),
+                        let peer = Peer {
+                            pc: Arc::new(api.new_peer_connection(rtc_config).await.for_peer(&uuid)?),
                             uuid: uuid.clone(),
                             output_tracks: HashMap::new(),
-                            tx,
+                            published_tracks: HashMap::new(),
+                            room: room.clone(),
+                            kind,
+                            permissions,
+                            name,
+                            track_sources: sources,
+                            negotiation: Negotiation::default(),
+                            recording: None,
+                            tx: Arc::clone(&tx),
                         };
-                        let pc = Arc::clone(&peer.pc);
 
-                        let offer = RTCSessionDescription::offer(sdp).unwrap();
-                        pc.set_remote_description(offer).await.unwrap();
-
-                        let mut media = vec![];
-                        media.push("audio");
-                        media.push("video");
-
-                        // Step 2: Add an tracks to this peer, for every other peer
-                        for (_key, p) in &peers {
-                            for s in media.clone() {
-                                let output_track = Arc::new(TrackLocalStaticRTP::new(
-                                        RTCRtpCodecCapability {
-                                            mime_type: if s == "video" {
-                                                MIME_TYPE_VP8.to_owned()
-                                            } else {
-                                                MIME_TYPE_OPUS.to_owned()
-                                            },
-                                            ..Default::default()
-                                        },
-                                        format!("track-{}", s),
-                                        "webrtc-rs".to_owned(),
-                                ));
-
-                                let rtp_sender = pc
-                                    .add_track(Arc::clone(&output_track) as Arc<dyn TrackLocal + Send + Sync>)
-                                    .await?;
-
-                                let m = s.to_owned();
-                                tokio::spawn(async move {
-                                    let mut rtcp_buf = vec![0u8; 1500];
-                                    while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-                                    println!("{} rtp_sender.read loop exit", m);
-                                    Result::<()>::Ok(())
-                                });
-
-                                peer.output_tracks.insert(s.to_owned(), output_track);
-                            }
+                        // The peer isn't in the room yet, so it has to be told about the failure
+                        // and detached from the room's tracks here.
+                        if let Err(err) = self.join(peer, sdp).await {
+                            metrics::NEGOTIATION_FAILURES.inc();
+                            let _ = tx.send(SocketMessage::error(err.error.to_string()));
+                            self.close_if_empty(&room);
+                            self.pending_candidates.remove(&uuid);
+                            return Err(err);
                         }
 
-                        // Step 3: Add 1 track to every other peer
-                        add_track_to_other_peers(&mut peers, uuid.to_owned()).await.unwrap();
+                        self.add_pending_candidates(&uuid).await?;
+                    }
+                }
+            }
+            ReceiveAnswer { uuid, sdp } => {
+                let peer = self.rooms.peer_mut(&uuid).ok_or_else(|| PeerError::unknown_peer(&uuid))?;
+                if !peer.negotiation.offer_outstanding {
+                    return Err(PeerError::new(&uuid, anyhow!("answer without an outstanding offer")));
+                }
 
-                        set_pc_callbacks(&mut peer, peer_chan_tx.clone()).await.unwrap();
+                let pc = Arc::clone(&peer.pc);
+                let renegotiate = peer.negotiation.renegotiate;
+                peer.negotiation = Negotiation::default();
 
-                        let answer = pc.create_answer(None).await?;
-                        let answer_string = serde_json::to_string(&answer)?;
+                pc.set_remote_description(sdp).await.or_disconnect(&uuid)?;
+                self.add_pending_candidates(&uuid).await?;
 
-                        pc.set_local_description(answer).await.unwrap();
+                // Send the offer that was held back while this one was outstanding
+                if renegotiate {
+                    self.peer_chan_tx.send(SendOffer { uuid: uuid.to_owned() }).for_peer(&uuid)?;
+                }
+            },
+            OnTrack { uuid, track, clock } => {
+                let publisher = self.rooms.peer(&uuid).ok_or_else(|| PeerError::unknown_peer(&uuid))?;
+                let track_id = track.id().await;
+                // The track was offered anyway, it just isn't read or forwarded
+                if !publisher.permissions.publish {
+                    return Err(PeerError::new(&uuid, anyhow!("publishing track {} needs the publish permission", track_id)));
+                }
+                let rid = track.rid().to_owned();
+                let layer = Arc::new(TrackLayer {
+                    keyframes: KeyframeRequester::new(&publisher.pc, track.ssrc()),
+                    track: Arc::clone(&track),
+                    bytes: AtomicU64::new(0),
+                    recent_bytes: AtomicU64::new(0),
+                    ended: AtomicBool::new(false),
+                    clock,
+                });
 
-                        tx_clone.send(SocketMessage {
-                            event: String::from("answer"),
-                            data: answer_string.to_owned(),
-                            uuid: uuid.to_owned()
-                        }).unwrap();
+                match publisher.published_tracks.get(&track_id).cloned() {
+                    // Another simulcast layer of a track that's already being forwarded
+                    Some(published) => {
+                        log::info!("Adding layer {:?} to track {}", rid, track_id);
+                        published.layers.write().await.insert(rid.to_owned(), Arc::clone(&layer));
+                        spawn_layer_forwarder(&published, rid, layer);
+                    }
+                    None => {
+                        let mut layers = HashMap::new();
+                        layers.insert(rid.to_owned(), Arc::clone(&layer));
 
-                        peers.insert(uuid.to_owned(), peer);
+                        let source = publisher.track_sources
+                            .get(&track_id)
+                            .copied()
+                            .unwrap_or_else(|| TrackSource::default_for(track.kind()));
+
+                        let published = PublishedTrack {
+                            id: track_id.to_owned(),
+                            kind: track.kind(),
+                            codec: track.codec().await.capability,
+                            layers: Arc::new(RwLock::new(layers)),
+                            source,
+                            stream_id: source.stream_id(&uuid),
+                            outputs: Arc::new(RwLock::new(HashMap::new())),
+                            recorder: Arc::new(StdMutex::new(None)),
+                        };
+
+                        // Start forwarding before handing out output tracks, so a subscriber that
+                        // fails to take the track doesn't stop the rest of the room getting it
+                        spawn_layer_forwarder(&published, rid, layer);
+                        if published.kind == RTPCodecType::Video {
+                            spawn_layer_ranking(&published);
+                        }
+
+                        if let Some(publisher) = self.rooms.peer_mut(&uuid) {
+                            publisher.published_tracks.insert(track_id.to_owned(), published.clone());
+                            let room = publisher.room.to_owned();
+                            let _ = self.events.send(SfuEvent::TrackPublished {
+                                room: room.to_owned(),
+                                publisher: uuid.to_owned(),
+                                track_id,
+                                kind: published.kind.to_string(),
+                            });
+
+                            let recording = self.rooms.room(&room).map_or(false, |r| r.recording);
+                            if let Some(publisher) = self.rooms.peer_mut(&uuid).filter(|_| recording) {
+                                start_track_recording(&self.config, &room, publisher, &published).await;
+                            }
+                        }
+
+                        // Give every other peer in the room its own output track for this source track
+                        if let Some(peers) = self.rooms.peers_of(&uuid) {
+                            broadcast(peers, published.published_event(&uuid));
+                            for err in add_track_to_other_peers(peers, &uuid, &published).await {
+                                self.report(err).await;
+                            }
+                        }
                     }
                 }
-            }
-            ReceiveAnswer { uuid, sdp } => {
-                let peer = peers.get(&uuid).unwrap();
-                let pc = Arc::clone(&peer.pc);
+            },
+            SelectLayer { uuid, publisher, track_id, layer } => {
+                let room = self.rooms.peer(&uuid).ok_or_else(|| PeerError::unknown_peer(&uuid))?.room.to_owned();
+                let published = self.rooms
+                    .peer(&publisher)
+                    .filter(|p| p.room == room)
+                    .and_then(|p| p.published_tracks.get(&track_id))
+                    .ok_or_else(|| PeerError::new(&uuid, anyhow!("no track {} published by {}", track_id, publisher)))?;
 
-                let answer = RTCSessionDescription::answer(sdp).unwrap();
-                pc.set_remote_description(answer).await.unwrap();
+                log::info!("{} selecting {:?} layer of {}/{}", uuid, layer, publisher, track_id);
+                published.select_layer(&uuid, layer).await.for_peer(&uuid)?;
             },
-            OnTrack { uuid, track } => {
-                for (key, peer) in &peers {
-                    let t = track.clone();
+            CloseRoom { room } => {
+                let uuids: Vec<String> = self.rooms
+                    .peers_of_room(&room)
+                    .map(|peers| peers.keys().cloned().collect())
+                    .unwrap_or_default();
 
-                    let mut output_tracks = &peer.output_tracks;
+                log::info!("🚪 Closing room {} with {} peers", room, uuids.len());
+                for uuid in uuids {
+                    if let Some(peer) = self.rooms.peer(&uuid) {
+                        let _ = peer.tx.send(SocketMessage::RoomClosed { room: room.to_owned() });
+                    }
+                    if let Err(err) = self.leave(&uuid).await {
+                        self.report(err).await;
+                    }
+                }
+            },
+            StartRecording { room } => {
+                let room_state = match self.rooms.room_mut(&room) {
+                    Some(room_state) if !room_state.recording => room_state,
+                    _ => return Ok(()),
+                };
+                room_state.recording = true;
 
-                    let kind = if t.kind() == RTPCodecType::Audio {
-                        "audio"
-                    } else {
-                        "video"
-                    };
-                    let output_track = if let Some(output_track) = output_tracks.get(kind) {
-                        Arc::clone(output_track);
-
-                        let output_track2 = Arc::clone(&output_track);
-                        tokio::spawn(async move {
-                            println!(
-                                "Track has started, of type {}: {}",
-                                t.payload_type(),
-                                t.codec().await.capability.mime_type
-                            );
-                            // Read RTP packets being sent to webrtc-rs
-                            while let Ok((rtp, _)) = t.read_rtp().await {
-                                if let Err(err) = output_track2.write_rtp(&rtp).await {
-                                    println!("output track write_rtp got error: {}", err);
-                                    break;
-                                }
-                            }
+                log::info!("⏺ Recording room {}", room);
+                for publisher in room_state.peers.values_mut() {
+                    let tracks: Vec<PublishedTrack> = publisher.published_tracks.values().cloned().collect();
+                    for published in &tracks {
+                        start_track_recording(&self.config, &room, publisher, published).await;
+                    }
+                }
+                broadcast(&room_state.peers, SocketMessage::Recording { active: true });
+                let _ = self.events.send(SfuEvent::RecordingStarted { room });
+            },
+            StopRecording { room } => {
+                let room_state = match self.rooms.room_mut(&room) {
+                    Some(room_state) if room_state.recording => room_state,
+                    _ => return Ok(()),
+                };
+                room_state.recording = false;
 
-                            println!(
-                                "on_track finished, of type {}: {}",
-                                t.payload_type(),
-                                t.codec().await.capability.mime_type
-                            );
-                        });
+                log::info!("⏹ Stopped recording room {}", room);
+                for publisher in room_state.peers.values_mut() {
+                    for published in publisher.published_tracks.values() {
+                        stop_track_recording(published);
+                    }
+                    publisher.recording = None;
+                }
+                broadcast(&room_state.peers, SocketMessage::Recording { active: false });
+                let _ = self.events.send(SfuEvent::RecordingStopped { room });
+            },
+            Drain { done } => {
+                log::info!("🛑 Draining {} peers", self.rooms.uuids().len());
+                for uuid in self.rooms.uuids() {
+                    let signals = match self.rooms.peer(&uuid) {
+                        Some(peer) => {
+                            let _ = peer.tx.send(SocketMessage::Shutdown {});
+                            peer.kind.signals()
+                        }
+                        None => continue,
                     };
+                    // WHIP and WHEP clients can't be told to leave, so they're disconnected now
+                    // rather than holding up the drain
+                    if !signals {
+                        if let Err(err) = self.leave(&uuid).await {
+                            self.report(err).await;
+                        }
+                    }
+                }
+
+                self.drained = Some(done);
+                self.check_drained();
+            },
+            CloseAll { done } => {
+                log::info!("🛑 Disconnecting {} peers", self.rooms.uuids().len());
+                for uuid in self.rooms.uuids() {
+                    if let Err(err) = self.leave(&uuid).await {
+                        self.report(err).await;
+                    }
+                }
+
+                let _ = done.send(());
+            },
+            StartEgress { id, egress, done } => {
+                let result = self.start_egress(&id, &egress).await;
+                if let Err(err) = &result {
+                    log::warn!("Failed to start RTP egress of {}/{}: {}", egress.publisher, egress.track_id, err);
+                }
+                let _ = done.send(result.map_err(|e| e.to_string()));
+            },
+            StopEgress { id } => {
+                if let Some((publisher, track_id)) = self.egresses.remove(&id) {
+                    log::info!("⏹ Stopping RTP egress {} of {}/{}", id, publisher, track_id);
+                    if let Some(published) = self.rooms.peer(&publisher).and_then(|p| p.published_tracks.get(&track_id)) {
+                        published.outputs.write().await.remove(&id);
+                    }
+                }
+            },
+            PeerLeft { uuid } => {
+                self.leave(&uuid).await?;
+            },
+            ConnectionClosed { uuid, pc } => {
+                // Closing a connection on leaving fires this too, after the peer is gone or has
+                // rejoined with a new one
+                let current = self.rooms.peer(&uuid).map_or(false, |p| Arc::as_ptr(&p.pc) == pc.as_ptr());
+                if current {
+                    self.leave(&uuid).await?;
                 }
             },
         }
+
+        Ok(())
     }
 
-    Ok(())
+    // Send a published track to a UDP destination as plain RTP, fed by a forwarder alongside
+    // the subscribers' output tracks. Returns the SDP describing the stream. It stops with the
+    // track, or when told to.
+    async fn start_egress(&mut self, id: &str, egress: &RtpEgress) -> Result<String> {
+        if !(96..=127).contains(&egress.payload_type) {
+            return Err(anyhow!("payload type {} isn't a dynamic one, 96 to 127", egress.payload_type));
+        }
+
+        let published = self.rooms
+            .peer(&egress.publisher)
+            .filter(|p| p.room == egress.room)
+            .and_then(|p| p.published_tracks.get(&egress.track_id))
+            .cloned()
+            .ok_or_else(|| anyhow!("no track {} published by {} in room {}", egress.track_id, egress.publisher, egress.room))?;
+
+        let sdp = egress::sdp(egress, published.kind);
+        if let Some(path) = &egress.sdp_path {
+            std::fs::write(path, &sdp)?;
+        }
+
+        let sink = Arc::new(UdpSink::connect(egress.dest, egress.payload_type).await?);
+        let target = Layer::High.pick(&published.ranked_layers().await).cloned().unwrap_or_default();
+        let forwarder = Forwarder::new(
+            sink,
+            target.to_owned(),
+            published.kind == RTPCodecType::Video,
+            published.codec.clock_rate,
+        );
+        published.outputs.write().await.insert(id.to_owned(), Arc::new(Mutex::new(forwarder)));
+        self.egresses.insert(id.to_owned(), (egress.publisher.to_owned(), egress.track_id.to_owned()));
+
+        log::info!("📤 Sending {}/{} as RTP to {}", egress.publisher, egress.track_id, egress.dest);
+        published.request_keyframe(&target).await?;
+
+        Ok(sdp)
+    }
+
+    // Log an error, tell the peer it was for, and disconnect the peer if it can't recover.
+    pub async fn report(&mut self, err: PeerError) {
+        log::warn!("⚠️ Error handling command for {}", err);
+
+        if let Some(peer) = self.rooms.peer(&err.uuid) {
+            let _ = peer.tx.send(SocketMessage::error(err.error.to_string()));
+        }
+
+        if err.disconnect {
+            metrics::NEGOTIATION_FAILURES.inc();
+            if let Err(err) = self.leave(&err.uuid).await {
+                log::warn!("⚠️ Error disconnecting {}", err);
+            }
+        }
+    }
+
+    // Set up a new peer's connection from its offer, subscribe it to the room's tracks and
+    // introduce it to the room. On failure the peer is torn down without joining.
+    async fn join(&mut self, mut peer: Peer, sdp: RTCSessionDescription) -> Result<(), PeerError> {
+        let uuid = peer.uuid.to_owned();
+        let room = peer.room.to_owned();
+
+        if let Err(err) = self.negotiate_join(&mut peer, sdp).await {
+            if let Err(e) = remove_peer(self.rooms.join(&room), peer).await {
+                log::warn!("⚠️ Error tearing down {}: {:#}", uuid, e);
+            }
+            return Err(err);
+        }
+
+        // Introduce the peer and the room to each other
+        if let Some(peers) = self.rooms.peers_of_room(&room) {
+            if peer.kind.signals() {
+                for p in peers.values() {
+                    let _ = peer.tx.send(p.joined_event());
+                    for published in p.published_tracks.values() {
+                        let _ = peer.tx.send(published.published_event(&p.uuid));
+                    }
+                }
+            }
+            broadcast(peers, peer.joined_event());
+        }
+
+        let _ = self.events.send(SfuEvent::PeerJoined {
+            uuid: peer.uuid.to_owned(),
+            room: peer.room.to_owned(),
+            name: peer.name.to_owned(),
+        });
+        self.rooms.insert(peer);
+
+        Ok(())
+    }
+
+    async fn negotiate_join(&mut self, peer: &mut Peer, sdp: RTCSessionDescription) -> Result<(), PeerError> {
+        let uuid = peer.uuid.to_owned();
+        let pc = Arc::clone(&peer.pc);
+
+        pc.set_remote_description(sdp).await.for_peer(&uuid)?;
+
+        // Subscribe this peer to every track already published in the call
+        for (publisher_uuid, p) in self.rooms.join(&peer.room).iter() {
+            if !peer.kind.subscribes_to(publisher_uuid) || !peer.permissions.subscribe {
+                continue;
+            }
+            for published in p.published_tracks.values() {
+                add_output_track(peer, publisher_uuid, published).await.for_peer(&uuid)?;
+            }
+        }
+
+        set_pc_callbacks(peer, self.peer_chan_tx.clone()).await.for_peer(&uuid)?;
+
+        let answer = pc.create_answer(None).await.for_peer(&uuid)?;
+
+        if peer.kind.signals() {
+            pc.set_local_description(answer.clone()).await.for_peer(&uuid)?;
+
+            peer.tx.send(SocketMessage::Answer {
+                uuid: uuid.to_owned(),
+                sdp: answer
+            }).for_peer(&uuid)?;
+        } else {
+            // Hand back the answer once it contains all of our candidates, without
+            // holding up the other peers while gathering finishes.
+            let mut gather_complete = pc.gathering_complete_promise().await;
+            pc.set_local_description(answer).await.for_peer(&uuid)?;
+
+            let tx = Arc::clone(&peer.tx);
+            tokio::spawn(async move {
+                let _ = gather_complete.recv().await;
+                if let Some(answer) = pc.local_description().await {
+                    let _ = tx.send(SocketMessage::Answer {
+                        uuid,
+                        sdp: answer
+                    });
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    // Add the candidates that arrived for a peer before its remote description was set.
+    async fn add_pending_candidates(&mut self, uuid: &str) -> Result<(), PeerError> {
+        let candidates = match self.pending_candidates.remove(uuid) {
+            Some(candidates) => candidates,
+            None => return Ok(()),
+        };
+        let peer = self.rooms.peer(uuid).ok_or_else(|| PeerError::unknown_peer(uuid))?;
+
+        // One bad candidate shouldn't cost the peer the rest of them
+        log::debug!("Adding {} buffered candidates for {}", candidates.len(), uuid);
+        let mut result = Ok(());
+        for candidate in candidates {
+            if let Err(err) = peer.pc.add_ice_candidate(candidate).await {
+                result = Err(PeerError::new(uuid, err));
+            }
+        }
+
+        result
+    }
+
+    fn close_if_empty(&mut self, room: &str) {
+        if self.rooms.close_if_empty(room) {
+            let _ = self.events.send(SfuEvent::RoomClosed { room: room.to_owned() });
+        }
+    }
+
+    // Remove a peer from its room and tear it down. The socket and the connection can both report
+    // the same peer leaving, so this may be called for a peer that's already gone.
+    async fn leave(&mut self, uuid: &str) -> Result<(), PeerError> {
+        self.pending_candidates.remove(uuid);
+        // Its tracks' forwarders go with it, egresses included
+        self.egresses.retain(|_, (publisher, _)| publisher != uuid);
+
+        if let Some((peer, peers)) = self.rooms.leave(uuid) {
+            log::info!("👋 Peer {} left room {}, tearing down.", uuid, peer.room);
+            let room = peer.room.to_owned();
+            for published in peer.published_tracks.values() {
+                stop_track_recording(published);
+                broadcast(peers, SocketMessage::TrackUnpublished {
+                    publisher: uuid.to_owned(),
+                    track_id: published.id.to_owned(),
+                });
+                let _ = self.events.send(SfuEvent::TrackUnpublished {
+                    room: room.to_owned(),
+                    publisher: uuid.to_owned(),
+                    track_id: published.id.to_owned(),
+                });
+            }
+            broadcast(peers, SocketMessage::ParticipantLeft { uuid: uuid.to_owned() });
+            let _ = self.events.send(SfuEvent::PeerLeft {
+                uuid: uuid.to_owned(),
+                room: room.to_owned(),
+            });
+            let result = remove_peer(peers, peer).await.for_peer(uuid);
+            self.close_if_empty(&room);
+            self.check_drained();
+            result?;
+        }
+
+        Ok(())
+    }
+
+    // Signal a shutdown in progress once everyone has left
+    fn check_drained(&mut self) {
+        if self.rooms.is_empty() {
+            if let Some(done) = &self.drained {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+// Send a roster or track event to every peer in the room with a signaling channel.
+fn broadcast(peers: &HashMap<String, Peer>, message: SocketMessage) {
+    for p in peers.values() {
+        if p.kind.signals() {
+            let _ = p.tx.send(message.clone());
+        }
+    }
 }
 
-async fn add_track_to_other_peers(peers: &mut HashMap<String, Peer>, uuid: String) -> anyhow::Result<()> {
-    let mut media = vec![];
-    media.push("audio");
-    media.push("video");
+// Subscribe the rest of the room to a new track. A subscriber that fails to take it doesn't
+// stop the others, its error is returned for reporting.
+async fn add_track_to_other_peers(peers: &mut HashMap<String, Peer>, uuid: &str, published: &PublishedTrack) -> Vec<PeerError> {
+    let mut errors = vec![];
 
     for (key, p) in peers {
-        if key == uuid.as_str() {
+        // Peers without signaling can't be renegotiated to carry the new track
+        if key == uuid || !p.kind.signals() || !p.kind.subscribes_to(uuid) || !p.permissions.subscribe {
             continue;
-        } else {
-            for s in media.clone() {
-                let output_track = Arc::new(TrackLocalStaticRTP::new(
-                        RTCRtpCodecCapability {
-                            mime_type: if s == "video" {
-                                MIME_TYPE_VP8.to_owned()
-                            } else {
-                                MIME_TYPE_OPUS.to_owned()
-                            },
-                            ..Default::default()
-                        },
-                        format!("track-{}", s),
-                        "webrtc-rs".to_owned(),
-                ));
-
-                let rtp_sender = p.pc
-                    .add_track(Arc::clone(&output_track) as Arc<dyn TrackLocal + Send + Sync>)
-                    .await?;
-
-                let m = s.to_owned();
-                tokio::spawn(async move {
-                    let mut rtcp_buf = vec![0u8; 1500];
-                    while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-                    println!("{} rtp_sender.read loop exit", m);
-                    Result::<()>::Ok(())
-                });
+        }
 
-                p.output_tracks.insert(s.to_owned(), output_track);
+        if let Err(err) = add_output_track(p, uuid, published).await {
+            errors.push(PeerError::new(key, err));
+        }
+    }
+
+    errors
+}
+
+// Read RTP packets from one layer of a published track, and forward them to every subscriber
+// that has that layer selected.
+fn spawn_layer_forwarder(published: &PublishedTrack, rid: String, layer: Arc<TrackLayer>) {
+    let outputs = Arc::clone(&published.outputs);
+    let recorder = Arc::clone(&published.recorder);
+    let kind = published.kind.to_string();
+    tokio::spawn(async move {
+        let track = &layer.track;
+        let packets_forwarded = metrics::RTP_PACKETS.with_label_values(&[&kind]);
+        let bytes_forwarded = metrics::RTP_BYTES.with_label_values(&[&kind]);
+        log::debug!(
+            "Track has started, of type {}: {}, rid {:?}",
+            track.payload_type(),
+            track.codec().await.capability.mime_type,
+            rid
+        );
+
+        while let Ok((rtp, _)) = track.read_rtp().await {
+            layer.bytes.fetch_add(rtp.payload.len() as u64, Ordering::Relaxed);
+
+            let wants_keyframe = match recorder.lock().unwrap().as_ref().filter(|r| r.rid == rid) {
+                Some(recorder) => {
+                    if let Err(err) = recorder.write_rtp(&rtp, &layer.clock) {
+                        log::warn!("Failed to record to {}: {}", recorder.path.display(), err);
+                    }
+                    recorder.wants_keyframe()
+                }
+                None => false,
+            };
+            // Recording starts on a keyframe
+            if wants_keyframe {
+                if let Err(err) = layer.keyframes.request().await {
+                    log::warn!("Failed to request a keyframe: {}", err);
+                }
+            }
+
+            for (subscriber, forwarder) in outputs.read().await.iter() {
+                let mut forwarder = forwarder.lock().await;
+                if let Some(packet) = forwarder.rewrite(&rid, &rtp) {
+                    match forwarder.track.write_rtp(&packet).await {
+                        Ok(_) => {
+                            packets_forwarded.inc();
+                            bytes_forwarded.inc_by(packet.payload.len() as u64);
+                        }
+                        Err(err) => log::warn!("output track write_rtp for {} got error: {}", subscriber, err),
+                    }
+                }
+            }
+        }
+
+        layer.ended.store(true, Ordering::Relaxed);
+
+        // Finish the file if this was the layer being recorded
+        let finished = {
+            let mut recorder = recorder.lock().unwrap();
+            match recorder.as_ref() {
+                Some(r) if r.rid == rid => recorder.take(),
+                _ => None,
+            }
+        };
+        if let Some(recorder) = finished {
+            recorder.close();
+        }
+        log::debug!(
+            "on_track finished, of type {}: {}, rid {:?}",
+            track.payload_type(),
+            track.codec().await.capability.mime_type,
+            rid
+        );
+    });
+}
+
+// Start recording a published track, from its best layer. In the WebM format a publisher's
+// camera and microphone go into one file. A track arriving once that file has started, like a
+// camera turned back on, rolls the publisher over to a new file with all of their tracks in it.
+async fn start_track_recording(config: &Config, room: &str, publisher: &mut Peer, published: &PublishedTrack) {
+    let rid = Layer::High.pick(&published.ranked_layers().await).cloned().unwrap_or_default();
+
+    let recorder = match config.recording_format {
+        RecordingFormat::Raw => Recorder::raw(&config.recording_dir, room, &publisher.uuid, &published.id, &published.codec, rid.to_owned()),
+        // Screen shares would need a file of their own
+        RecordingFormat::Webm if published.source == TrackSource::Screen => Ok(None),
+        RecordingFormat::Webm => {
+            let video = published.kind == RTPCodecType::Video;
+            let recording = match &publisher.recording {
+                Some(recording) if recording.lock().unwrap().accepts(video) => Ok(Arc::clone(recording)),
+                Some(_) => {
+                    log::info!("Rolling the recording of {} over to a new file for track {}", publisher.uuid, published.id);
+                    start_peer_recording(config, room, publisher, published).await
+                }
+                None => WebmRecording::create(&config.recording_dir, room, &publisher.uuid).map(|r| Arc::new(StdMutex::new(r))),
+            };
+            recording.map(|recording| {
+                publisher.recording = Some(Arc::clone(&recording));
+                Recorder::webm(&recording, &published.codec, rid.to_owned())
+            })
+        }
+    };
+
+    match recorder {
+        Ok(Some(recorder)) => {
+            log::info!("⏺ Recording track {} of {} to {}", published.id, publisher.uuid, recorder.path.display());
+            if let Some(previous) = published.recorder.lock().unwrap().replace(recorder) {
+                previous.close();
+            }
+            // The video can't be decoded until the next keyframe
+            if let Err(err) = published.request_keyframe(&rid).await {
+                log::warn!("Failed to request a keyframe: {}", err);
+            }
+        }
+        Ok(None) => log::warn!("Can't record track {} of {} with codec {}", published.id, publisher.uuid, published.codec.mime_type),
+        Err(err) => log::warn!("Failed to start recording track {} of {}: {}", published.id, publisher.uuid, err),
+    }
+}
+
+// Start a new WebM file for a publisher, moving their other camera or microphone track over to
+// it. The new track is left for the caller to add.
+async fn start_peer_recording(config: &Config, room: &str, publisher: &Peer, new_track: &PublishedTrack) -> Result<Arc<StdMutex<WebmRecording>>> {
+    let recording = Arc::new(StdMutex::new(WebmRecording::create(&config.recording_dir, room, &publisher.uuid)?));
+
+    let others = publisher.published_tracks
+        .values()
+        .filter(|t| t.id != new_track.id && t.kind != new_track.kind && t.source != TrackSource::Screen)
+        .take(1);
+    for published in others {
+        let rid = Layer::High.pick(&published.ranked_layers().await).cloned().unwrap_or_default();
+        if let Some(recorder) = Recorder::webm(&recording, &published.codec, rid) {
+            if let Some(previous) = published.recorder.lock().unwrap().replace(recorder) {
+                previous.close();
             }
         }
     }
 
+    Ok(recording)
+}
+
+fn stop_track_recording(published: &PublishedTrack) {
+    let recorder = published.recorder.lock().unwrap().take();
+    if let Some(recorder) = recorder {
+        recorder.close();
+    }
+}
+
+// Read the RTCP a publisher sends for one layer of a track, keeping its clock up to date from
+// the sender reports. Reading it also lets the receiver's interceptors see it. Stops once the
+// track ends.
+fn spawn_rtcp_reader(receiver: Arc<RTCRtpReceiver>, track: &TrackRemote, clock: Arc<SenderClock>) {
+    let rid = track.rid().to_owned();
+    let ssrc = track.ssrc();
+    tokio::spawn(async move {
+        loop {
+            let packets = if rid.is_empty() {
+                receiver.read_rtcp().await
+            } else {
+                receiver.read_simulcast_rtcp(&rid).await
+            };
+            let (packets, _) = match packets {
+                Ok(packets) => packets,
+                Err(_) => break,
+            };
+
+            for packet in packets {
+                if let Some(report) = packet.as_any().downcast_ref::<SenderReport>() {
+                    if report.ssrc == ssrc {
+                        clock.update(report);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Periodically re-rank the layers of a simulcast track by what each carried over the last
+// interval, and re-point subscribers at their preferred layer. Stops once every layer has ended.
+fn spawn_layer_ranking(published: &PublishedTrack) {
+    let published = published.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(LAYER_RANKING_INTERVAL).await;
+
+            {
+                let layers = published.layers.read().await;
+                if layers.values().all(|l| l.ended.load(Ordering::Relaxed)) {
+                    break;
+                }
+                for layer in layers.values() {
+                    layer.recent_bytes.store(layer.bytes.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+                }
+            }
+
+            if let Err(err) = published.retarget().await {
+                log::warn!("Failed to retarget layers of track {}: {}", published.id, err);
+            }
+        }
+    });
+}
+
+// Create an output track on the subscriber for one of the publisher's tracks, and register it
+// with the track's forwarding loops.
+async fn add_output_track(subscriber: &mut Peer, publisher_uuid: &str, published: &PublishedTrack) -> anyhow::Result<()> {
+    let key = (publisher_uuid.to_owned(), published.id.to_owned());
+
+    if subscriber.output_tracks.contains_key(&key) {
+        return Ok(());
+    }
+
+    let output_track = Arc::new(TrackLocalStaticRTP::new(
+            published.codec.clone(),
+            published.id.to_owned(),
+            published.stream_id.to_owned(),
+    ));
+
+    let rtp_sender = subscriber.pc
+        .add_track(Arc::clone(&output_track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await?;
+
+    // Start subscribers on the best layer, they can ask for a lower one
+    let target = Layer::High.pick(&published.ranked_layers().await).cloned().unwrap_or_default();
+    let forwarder = Arc::new(Mutex::new(Forwarder::new(
+            Arc::clone(&output_track) as Arc<dyn TrackLocalWriter + Send + Sync>,
+            target.to_owned(),
+            published.kind == RTPCodecType::Video,
+            published.codec.clock_rate,
+    )));
+
+    let sender = Arc::clone(&rtp_sender);
+    let kind = published.kind;
+    let published_clone = published.clone();
+    let forwarder_clone = Arc::clone(&forwarder);
+    // Read the subscriber's RTCP, passing keyframe requests on to the publisher. Exits once the
+    // sender is stopped, either by remove_track or by the connection closing.
+    tokio::spawn(async move {
+        while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+            if kind != RTPCodecType::Video {
+                continue;
+            }
+
+            let wants_keyframe = packets.iter().any(|p| {
+                p.as_any().downcast_ref::<PictureLossIndication>().is_some()
+                    || p.as_any().downcast_ref::<FullIntraRequest>().is_some()
+            });
+
+            if wants_keyframe {
+                let target = forwarder_clone.lock().await.target.to_owned();
+                if let Err(err) = published_clone.request_keyframe(&target).await {
+                    log::warn!("Failed to request a keyframe: {}", err);
+                }
+            }
+        }
+        log::debug!("{} rtp_sender.read loop exit", kind);
+        Result::<()>::Ok(())
+    });
+
+    published.outputs.write().await.insert(subscriber.uuid.to_owned(), forwarder);
+    subscriber.output_tracks.insert(key, OutputTrack { track: output_track, sender });
+
+    // Video can't start until the publisher sends a keyframe
+    published.request_keyframe(&target).await?;
+
+    Ok(())
+}
+
+// Detach a peer that has left from everyone else in the call and close its connection.
+async fn remove_peer(peers: &mut HashMap<String, Peer>, peer: Peer) -> anyhow::Result<()> {
+    for (_key, p) in peers.iter_mut() {
+        // Stop forwarding this peer's tracks to the subscriber. Removing the transceiver's track
+        // triggers renegotiation on the subscriber's connection.
+        let keys: Vec<TrackKey> = p.output_tracks
+            .keys()
+            .filter(|(publisher_uuid, _)| *publisher_uuid == peer.uuid)
+            .cloned()
+            .collect();
+
+        for key in keys {
+            if let Some(output) = p.output_tracks.remove(&key) {
+                if let Err(err) = p.pc.remove_track(&output.sender).await {
+                    log::warn!("Failed to remove track {:?} from {}: {}", key, p.uuid, err);
+                }
+            }
+        }
+
+        // Stop forwarding the subscriber's tracks to this peer
+        for published in p.published_tracks.values() {
+            published.outputs.write().await.remove(&peer.uuid);
+        }
+    }
+
+    // Closing the connection ends the forwarding loops reading from its remote tracks and the
+    // rtcp read loops on its senders.
+    for published in peer.published_tracks.values() {
+        published.outputs.write().await.clear();
+    }
+    peer.pc.close().await?;
+
     Ok(())
 }
 
@@ -256,14 +1385,14 @@ async fn set_pc_callbacks(peer: &mut Peer, peer_chan_tx: Sender<PeerChanCommand>
     let mut uuid = peer.uuid.clone();
     peer.pc
         .on_negotiation_needed(Box::new(move || {
-            println!("🔥 Peer Connection needs negotiation.");
+            log::debug!("🔥 Peer Connection needs negotiation.");
             let cloned_tx = tx_clone.clone();
             let cloned_id = uuid.clone();
 
             Box::pin(async move {
-                cloned_tx.send(PeerChanCommand::SendOffer {
+                let _ = cloned_tx.send(PeerChanCommand::SendOffer {
                     uuid: cloned_id.to_owned(),
-                }).unwrap();
+                });
             })
         }))
     .await;
@@ -277,64 +1406,54 @@ async fn set_pc_callbacks(peer: &mut Peer, peer_chan_tx: Sender<PeerChanCommand>
             let cloned_id = uuid.clone();
 
             Box::pin(async move {
-                if !candidate.is_none() {
-                    cloned_tx.send(PeerChanCommand::SendIceCandidate {
-                        uuid: cloned_id.to_owned(),
-                        candidate: serde_json::to_string(&candidate.unwrap()).unwrap(),
-                    }).unwrap();
+                if let Some(candidate) = candidate {
+                    match candidate.to_json() {
+                        Ok(candidate) => {
+                            let _ = cloned_tx.send(PeerChanCommand::SendIceCandidate {
+                                uuid: cloned_id.to_owned(),
+                                candidate,
+                            });
+                        }
+                        Err(err) => log::warn!("Failed to serialize ice candidate for {}: {}", cloned_id, err),
+                    }
                 }
             })
         })).await;
 
-    // Set a handler for when a new remote track starts, this handler copies inbound RTP packets,
-    // replaces the SSRC and sends them back
-    let pc = Arc::downgrade(&peer.pc);
+    // Set a handler for when a new remote track starts, the router forwards its RTP packets to
+    // every subscriber
     let mut tx_clone = peer_chan_tx.clone();
     uuid = peer.uuid.clone();
     peer.pc
         .on_track(Box::new(
-                move |track: Option<Arc<TrackRemote>>, _receiver: Option<Arc<RTCRtpReceiver>>| {
+                move |track: Option<Arc<TrackRemote>>, receiver: Option<Arc<RTCRtpReceiver>>| {
                     if let Some(track) = track {
-                        // Send a PLI on an interval so that the publisher is pushing a keyframe every rtcpPLIInterval
-                        // This is a temporary fix until we implement incoming RTCP events, then we would push a PLI only when a viewer requests it
-                        let media_ssrc = track.ssrc();
-
-                        if track.kind() == RTPCodecType::Video {
-                            let pc2 = pc.clone();
-                            tokio::spawn(async move {
-                                let mut result = Result::<usize>::Ok(0);
-                                while result.is_ok() {
-                                    let timeout = tokio::time::sleep(Duration::from_secs(3));
-                                    tokio::pin!(timeout);
-
-                                    tokio::select! {
-                                        _ = timeout.as_mut() =>{
-                                            if let Some(pc) = pc2.upgrade(){
-                                                result = pc.write_rtcp(&[Box::new(PictureLossIndication{
-                                                    sender_ssrc: 0,
-                                                    media_ssrc,
-                                                })]).await.map_err(Into::into);
-                                            }else{
-                                                break;
-                                            }
-                                        }
-                                    };
-                                }
-                            });
+                        let clock = Arc::new(SenderClock::default());
+                        if let Some(receiver) = receiver {
+                            spawn_rtcp_reader(receiver, &track, Arc::clone(&clock));
                         }
-
-                        tx_clone.send(PeerChanCommand::OnTrack {
+                        let _ = tx_clone.send(PeerChanCommand::OnTrack {
                             uuid: uuid.to_owned(),
-                            track
-                        }).unwrap();
+                            track,
+                            clock,
+                        });
                     }
                     Box::pin(async {})
                 },
     )).await;
 
+    let tx_clone = peer_chan_tx.clone();
+    uuid = peer.uuid.clone();
+    let pc = Arc::downgrade(&peer.pc);
     peer.pc
         .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-            println!("Peer Connection State has changed: {}", s);
+            log::debug!("Peer Connection State has changed: {}", s);
+            if s == RTCPeerConnectionState::Failed || s == RTCPeerConnectionState::Closed {
+                let _ = tx_clone.send(PeerChanCommand::ConnectionClosed {
+                    uuid: uuid.to_owned(),
+                    pc: pc.clone(),
+                });
+            }
             Box::pin(async {})
         })).await;
     Ok(())
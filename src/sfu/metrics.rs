@@ -0,0 +1,73 @@
+use anyhow::Result;
+use flume::Sender;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use crate::PeerChanCommand;
+
+// Scraped by Prometheus, in its text exposition format
+pub const METRICS_PATH: &str = "/metrics";
+
+lazy_static! {
+    pub static ref WEBSOCKETS: IntGauge = register_int_gauge!(
+        "sfu_websocket_connections", "Websocket connections currently open"
+    ).unwrap();
+    pub static ref WEBSOCKETS_TOTAL: IntCounter = register_int_counter!(
+        "sfu_websocket_connections_total", "Websocket connections accepted"
+    ).unwrap();
+    pub static ref PEERS: IntGauge = register_int_gauge!(
+        "sfu_peers", "Peers in a room, over any transport"
+    ).unwrap();
+    pub static ref ROOMS: IntGauge = register_int_gauge!(
+        "sfu_rooms", "Rooms with at least one peer"
+    ).unwrap();
+    pub static ref RTP_PACKETS: IntCounterVec = register_int_counter_vec!(
+        "sfu_rtp_forwarded_packets_total", "RTP packets written to subscribers", &["kind"]
+    ).unwrap();
+    pub static ref RTP_BYTES: IntCounterVec = register_int_counter_vec!(
+        "sfu_rtp_forwarded_bytes_total", "RTP payload bytes written to subscribers", &["kind"]
+    ).unwrap();
+    pub static ref PLIS_SENT: IntCounter = register_int_counter!(
+        "sfu_rtcp_plis_sent_total", "Picture loss indications sent to publishers"
+    ).unwrap();
+    pub static ref NEGOTIATION_FAILURES: IntCounter = register_int_counter!(
+        "sfu_negotiation_failures_total", "Offers and answers that couldn't be applied or created"
+    ).unwrap();
+    pub static ref COMMAND_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "sfu_command_queue_depth", "Commands waiting on the router"
+    ).unwrap();
+}
+
+pub fn handle_metrics(peer_chan_tx: &Sender<PeerChanCommand>) -> Result<Response<Body>, anyhow::Error> {
+    // Only meaningful at the moment it's scraped
+    COMMAND_QUEUE_DEPTH.set(peer_chan_tx.len() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))?)
+}
+
+// Holds a gauge up by one for as long as it's alive
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use webrtc::media::io::ivf_reader::IVFReader;
+use webrtc::media::Sample;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+use crate::sfu::local::{opus_codec, vp8_codec, LocalPublisher};
+use crate::sfu::server::SfuHandle;
+
+// Files played into a room by a virtual participant, like an intro, hold music or test content.
+#[derive(Debug, Clone)]
+pub struct Playback {
+    pub room: String,
+    // Shown to the room like any participant's name
+    pub name: String,
+    // VP8 in IVF
+    pub video: Option<PathBuf>,
+    // Opus in Ogg
+    pub audio: Option<PathBuf>,
+    // Start over at the end of each file instead of leaving the room
+    pub looping: bool,
+}
+
+// Join a virtual participant to the room and start playing the files at real-time pace. It
+// leaves once every file has played, or can be removed early with the handle's leave.
+pub async fn start(handle: &SfuHandle, uuid: &str, playback: Playback) -> Result<()> {
+    if playback.video.is_none() && playback.audio.is_none() {
+        return Err(anyhow!("nothing to play"));
+    }
+
+    let publisher = Arc::new(LocalPublisher::new(handle, uuid, &playback.room).await?);
+
+    let mut tracks = vec![];
+    if let Some(path) = &playback.video {
+        tracks.push((Arc::new(TrackLocalStaticSample::new(vp8_codec(), "video".to_owned(), uuid.to_owned())), path.to_owned()));
+    }
+    if let Some(path) = &playback.audio {
+        tracks.push((Arc::new(TrackLocalStaticSample::new(opus_codec(), "audio".to_owned(), uuid.to_owned())), path.to_owned()));
+    }
+    for (track, _) in &tracks {
+        publisher.add_track(Arc::clone(track) as Arc<dyn TrackLocal + Send + Sync>).await?;
+    }
+
+    publisher.join(&playback.name).await?;
+
+    log::info!("▶️ {} playing into room {}", uuid, playback.room);
+    let players: Vec<_> = tracks
+        .into_iter()
+        .map(|(track, path)| {
+            let publisher = Arc::clone(&publisher);
+            let looping = playback.looping;
+            tokio::spawn(async move {
+                let result = if path.extension().map_or(false, |e| e == "ogg") {
+                    play_ogg(&track, &path, looping, &publisher).await
+                } else {
+                    play_ivf(&track, &path, looping, &publisher).await
+                };
+                if let Err(err) = result {
+                    log::warn!("Failed to play {}: {}", path.display(), err);
+                }
+            })
+        })
+        .collect();
+
+    tokio::spawn(async move {
+        futures::future::join_all(players).await;
+        log::info!("⏹ {} finished playing", publisher.uuid);
+        publisher.leave().await;
+    });
+
+    Ok(())
+}
+
+// Play VP8 frames when their timestamps say, each lasting until the next one is due
+async fn play_ivf(track: &TrackLocalStaticSample, path: &Path, looping: bool, publisher: &LocalPublisher) -> Result<()> {
+    loop {
+        let (mut reader, header) = IVFReader::new(BufReader::new(File::open(path)?))?;
+        // Timestamps count in units of the timebase, numerator / denominator seconds
+        let at = |timestamp: u64| {
+            let nanos = timestamp as u128 * header.timebase_numerator as u128 * 1_000_000_000
+                / header.timebase_denominator.max(1) as u128;
+            Duration::from_nanos(nanos as u64)
+        };
+        let start = Instant::now();
+        let mut duration = Duration::ZERO;
+
+        let mut next = reader.parse_next_frame().ok();
+        while let Some((frame, frame_header)) = next.take() {
+            let due = at(frame_header.timestamp);
+            // The last frame lasts as long as the one before it
+            next = reader.parse_next_frame().ok();
+            if let Some((_, following)) = &next {
+                duration = at(following.timestamp).saturating_sub(due);
+            }
+
+            tokio::time::sleep_until(start + due).await;
+            if publisher.is_stopped() {
+                return Ok(());
+            }
+
+            track.write_sample(&Sample {
+                data: frame.freeze(),
+                duration,
+                ..Default::default()
+            }).await?;
+        }
+
+        if !looping {
+            return Ok(());
+        }
+    }
+}
+
+// Play Opus a packet at a time, each once the ones before it have played out
+async fn play_ogg(track: &TrackLocalStaticSample, path: &Path, looping: bool, publisher: &LocalPublisher) -> Result<()> {
+    loop {
+        let mut packets = OggPackets::new(BufReader::new(File::open(path)?));
+        let start = Instant::now();
+        let mut due = Duration::ZERO;
+
+        while let Some(packet) = packets.next_packet()? {
+            // The identification and comment headers, and anything empty
+            if packet.is_empty() || packet.starts_with(b"OpusHead") || packet.starts_with(b"OpusTags") {
+                continue;
+            }
+
+            tokio::time::sleep_until(start + due).await;
+            if publisher.is_stopped() {
+                return Ok(());
+            }
+
+            let duration = opus_duration(&packet);
+            track.write_sample(&Sample {
+                data: Bytes::from(packet),
+                duration,
+                ..Default::default()
+            }).await?;
+            due += duration;
+        }
+
+        if !looping {
+            return Ok(());
+        }
+    }
+}
+
+// How long an Opus packet plays for, from the configuration and frame count in its TOC byte
+// (RFC 6716 section 3.1)
+fn opus_duration(packet: &[u8]) -> Duration {
+    let toc = match packet.first() {
+        Some(toc) => *toc,
+        None => return Duration::ZERO,
+    };
+
+    let config = (toc >> 3) as usize;
+    let frame_micros: u64 = match config {
+        // SILK
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config & 3],
+        // Hybrid
+        12..=15 => [10_000, 20_000][config & 1],
+        // CELT
+        _ => [2_500, 5_000, 10_000, 20_000][config & 3],
+    };
+    let frames = match toc & 3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |count| count & 0x3F) as u64,
+    };
+
+    Duration::from_micros(frame_micros * frames)
+}
+
+// Reads the packets out of an Ogg stream. Pages can hold several packets, and packets can run on
+// over several pages, so they're found from each page's segment table: a packet ends at the
+// first segment shorter than 255 bytes. Checksums aren't checked.
+struct OggPackets<R: Read> {
+    reader: R,
+    // The lengths of the current page's segments not read yet
+    segments: VecDeque<u8>,
+}
+
+impl<R: Read> OggPackets<R> {
+    fn new(reader: R) -> Self {
+        OggPackets {
+            reader,
+            segments: VecDeque::new(),
+        }
+    }
+
+    // The next packet, or None at the end of the stream
+    fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut packet = vec![];
+        loop {
+            while let Some(len) = self.segments.pop_front() {
+                let start = packet.len();
+                packet.resize(start + len as usize, 0);
+                self.reader.read_exact(&mut packet[start..])?;
+                if len < 255 {
+                    return Ok(Some(packet));
+                }
+            }
+
+            if !self.next_page()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Read the next page's header and segment table. False at the end of the stream.
+    fn next_page(&mut self) -> Result<bool> {
+        let mut header = [0u8; 27];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        if header[..4] != *b"OggS" {
+            return Err(anyhow!("not an Ogg page"));
+        }
+
+        let mut table = vec![0u8; header[26] as usize];
+        self.reader.read_exact(&mut table)?;
+        self.segments.extend(table);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn opus_duration_from_frame_count() {
+        // Config 1 is 20ms SILK frames
+        assert_eq!(opus_duration(&[0x08]), Duration::from_millis(20));
+        assert_eq!(opus_duration(&[0x09]), Duration::from_millis(40));
+        assert_eq!(opus_duration(&[0x0A]), Duration::from_millis(40));
+        // Code 3 takes the count from the next byte
+        assert_eq!(opus_duration(&[0x0B, 0x03]), Duration::from_millis(60));
+        // Config 28 is 2.5ms CELT frames
+        assert_eq!(opus_duration(&[0xE3, 0x04]), Duration::from_millis(10));
+        assert_eq!(opus_duration(&[]), Duration::ZERO);
+    }
+
+    fn page(segments: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.resize(26, 0);
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        page
+    }
+
+    #[test]
+    fn packet_continued_on_next_page() {
+        let mut stream = page(&[10, 255]);
+        stream.extend([1u8; 10]);
+        stream.extend([2u8; 255]);
+        stream.extend(page(&[45, 5]));
+        stream.extend([2u8; 45]);
+        stream.extend([3u8; 5]);
+
+        let mut packets = OggPackets::new(Cursor::new(stream));
+        assert_eq!(packets.next_packet().unwrap(), Some(vec![1u8; 10]));
+        assert_eq!(packets.next_packet().unwrap(), Some(vec![2u8; 300]));
+        assert_eq!(packets.next_packet().unwrap(), Some(vec![3u8; 5]));
+        assert_eq!(packets.next_packet().unwrap(), None);
+    }
+}
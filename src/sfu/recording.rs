@@ -0,0 +1,497 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use flume::Sender;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use webrtc::api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_VP8};
+use webrtc::media::io::ivf_reader::IVFFileHeader;
+use webrtc::media::io::ivf_writer::IVFWriter;
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::sample_builder::SampleBuilder;
+use webrtc::media::io::Writer;
+use webrtc::media::Sample;
+use webrtc::rtcp::sender_report::SenderReport;
+use webrtc::rtp::codecs::opus::OpusPacket;
+use webrtc::rtp::codecs::vp8::Vp8Packet;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use crate::sfu::webm::{WebmTrack, WebmWriter};
+
+// How many packets a frame can wait on a missing one before it's given up on
+const MAX_LATE_PACKETS: u16 = 128;
+
+// How many packets can wait on a recording's writer before new ones are dropped
+const WRITE_QUEUE: usize = 1024;
+
+const VP8_CLOCK_RATE: u32 = 90000;
+const OPUS_CLOCK_RATE: u32 = 48000;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    // A WebM file per participant, with their camera and microphone in sync
+    Webm,
+    // A file per track, IVF for VP8 and Ogg for Opus
+    Raw,
+}
+
+impl RecordingFormat {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "webm" => Ok(RecordingFormat::Webm),
+            "raw" => Ok(RecordingFormat::Raw),
+            _ => Err(anyhow!("unsupported recording format {:?}, expected webm or raw", s)),
+        }
+    }
+}
+
+// The latest RTCP sender report for a layer, relating its RTP timestamps to the publisher's
+// wallclock. A publisher's tracks all share that clock, which is what lines their audio and video
+// up with each other.
+#[derive(Debug, Default)]
+pub struct SenderClock(StdMutex<Option<(u64, u32)>>);
+
+impl SenderClock {
+    pub fn update(&self, report: &SenderReport) {
+        *self.0.lock().unwrap() = Some((report.ntp_time, report.rtp_time));
+    }
+
+    // The publisher's wallclock, in nanoseconds since the NTP epoch, at an RTP timestamp. None
+    // until the first sender report arrives.
+    pub fn wallclock(&self, rtp_time: u32, clock_rate: u32) -> Option<i64> {
+        let (ntp_time, report_rtp_time) = (*self.0.lock().unwrap())?;
+
+        let report_ns = (ntp_time >> 32) as i64 * 1_000_000_000 + (((ntp_time & 0xFFFF_FFFF) * 1_000_000_000) >> 32) as i64;
+        let elapsed = rtp_time.wrapping_sub(report_rtp_time) as i32 as i64;
+        Some(report_ns + elapsed * 1_000_000_000 / clock_rate.max(1) as i64)
+    }
+}
+
+// Records one layer of a published track, either to a file of its own or as a track of its
+// publisher's WebM file. The file is written on a thread of its own, so a slow disk never holds
+// up forwarding the track.
+pub struct Recorder {
+    // The simulcast layer being recorded
+    pub rid: String,
+    pub path: PathBuf,
+    packets: Sender<(Packet, Arc<SenderClock>)>,
+    // Kept up to date by the writer
+    wants_keyframe: Arc<AtomicBool>,
+}
+
+enum Output {
+    // The writers depacketize the RTP themselves, and IVF waits for a keyframe before writing
+    // anything
+    Raw(Box<dyn Writer + Send>),
+    Webm {
+        recording: Arc<StdMutex<WebmRecording>>,
+        track: usize,
+        frames: Frames,
+        clock_rate: u32,
+    },
+}
+
+// Reassembles frames from a track's RTP packets
+enum Frames {
+    Vp8(SampleBuilder<Vp8Packet>),
+    Opus(SampleBuilder<OpusPacket>),
+}
+
+impl Frames {
+    // The next complete frame, and whether it's a keyframe
+    fn pop(&mut self) -> Option<(Sample, bool)> {
+        match self {
+            // Bit 0 of the VP8 frame tag is clear on keyframes
+            Frames::Vp8(builder) => builder.pop().map(|sample| {
+                let keyframe = sample.data.first().map_or(false, |b| b & 0x01 == 0);
+                (sample, keyframe)
+            }),
+            Frames::Opus(builder) => builder.pop().map(|sample| (sample, true)),
+        }
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").field("rid", &self.rid).field("path", &self.path).finish()
+    }
+}
+
+impl Recorder {
+    // Start a file for a publisher's track, named by room, publisher and start time. None if
+    // the track's codec can't be recorded.
+    pub fn raw(dir: &Path, room: &str, publisher: &str, track_id: &str, codec: &RTCRtpCodecCapability, rid: String) -> Result<Option<Self>> {
+        let extension = if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+            "ivf"
+        } else if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+            "ogg"
+        } else {
+            return Ok(None);
+        };
+
+        let path = recording_path(dir, room, publisher, Some(track_id), extension)?;
+        let file = BufWriter::new(File::create(&path)?);
+
+        let writer: Box<dyn Writer + Send> = if extension == "ivf" {
+            Box::new(IVFWriter::new(file, &IVFFileHeader {
+                signature: *b"DKIF",
+                version: 0,
+                header_size: 32,
+                four_cc: *b"VP80",
+                // Players take the size from the frames themselves
+                width: 640,
+                height: 480,
+                timebase_denominator: 30,
+                timebase_numerator: 1,
+                // Not known yet, the writer fills in the count when it's closed
+                num_frames: 0,
+                unused: 0,
+            })?)
+        } else {
+            Box::new(OggWriter::new(file, codec.clock_rate, codec.channels.max(1) as u8)?)
+        };
+
+        Ok(Some(Recorder::spawn(rid, path, Output::Raw(writer))))
+    }
+
+    // Record a track into its publisher's WebM file. None if the track's codec can't be
+    // recorded, or the file already has a track of its kind.
+    pub fn webm(recording: &Arc<StdMutex<WebmRecording>>, codec: &RTCRtpCodecCapability, rid: String) -> Option<Self> {
+        // Both payload formats fix the RTP clock rate, whatever the codec parameters say
+        let (frames, kind, clock_rate) = if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+            (Frames::Vp8(SampleBuilder::new(MAX_LATE_PACKETS, Vp8Packet::default(), VP8_CLOCK_RATE)), TrackKind::Video, VP8_CLOCK_RATE)
+        } else if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+            let kind = TrackKind::Audio {
+                sample_rate: OPUS_CLOCK_RATE,
+                channels: codec.channels.max(1),
+            };
+            (Frames::Opus(SampleBuilder::new(MAX_LATE_PACKETS, OpusPacket::default(), OPUS_CLOCK_RATE)), kind, OPUS_CLOCK_RATE)
+        } else {
+            return None;
+        };
+
+        let (track, path) = {
+            let mut file = recording.lock().unwrap();
+            (file.add_track(kind)?, file.path.to_owned())
+        };
+
+        Some(Recorder::spawn(rid, path, Output::Webm {
+            recording: Arc::clone(recording),
+            track,
+            frames,
+            clock_rate,
+        }))
+    }
+
+    // Start the thread writing the output, which finishes it once the recorder is closed
+    fn spawn(rid: String, path: PathBuf, mut output: Output) -> Self {
+        let (packets, rx) = flume::bounded::<(Packet, Arc<SenderClock>)>(WRITE_QUEUE);
+        let wants_keyframe = Arc::new(AtomicBool::new(output.wants_keyframe()));
+
+        let file = path.to_owned();
+        let keyframe = Arc::clone(&wants_keyframe);
+        std::thread::spawn(move || {
+            while let Ok((packet, clock)) = rx.recv() {
+                if let Err(err) = output.write_rtp(&packet, &clock) {
+                    log::warn!("Failed to record to {}: {}", file.display(), err);
+                }
+                keyframe.store(output.wants_keyframe(), Ordering::Relaxed);
+            }
+            output.close(&file);
+        });
+
+        Recorder { rid, path, packets, wants_keyframe }
+    }
+
+    // Queue a packet for the writer. Packets are dropped while it's behind.
+    pub fn write_rtp(&self, packet: &Packet, clock: &Arc<SenderClock>) -> Result<()> {
+        self.packets
+            .try_send((packet.clone(), Arc::clone(clock)))
+            .map_err(|_| anyhow!("the writer is behind, dropped a packet"))
+    }
+
+    // Whether recording can't start until the publisher sends a keyframe
+    pub fn wants_keyframe(&self) -> bool {
+        self.wants_keyframe.load(Ordering::Relaxed)
+    }
+
+    // Stop recording. The writer finishes the file once the packets already queued are written.
+    pub fn close(self) {
+        drop(self.packets);
+    }
+}
+
+impl Output {
+    fn write_rtp(&mut self, packet: &Packet, clock: &SenderClock) -> Result<()> {
+        let (recording, track, frames, clock_rate) = match self {
+            Output::Raw(writer) => {
+                writer.write_rtp(packet)?;
+                return Ok(());
+            }
+            Output::Webm { recording, track, frames, clock_rate } => (recording, *track, frames, *clock_rate),
+        };
+
+        // Frames can't be placed on the file's timeline until the publisher has sent a sender
+        // report for the track
+        let mut recording = recording.lock().unwrap();
+        recording.tracks[track].synced |= clock.wallclock(packet.header.timestamp, clock_rate).is_some();
+
+        match frames {
+            Frames::Vp8(builder) => builder.push(packet.clone()),
+            Frames::Opus(builder) => builder.push(packet.clone()),
+        }
+
+        while let Some((sample, keyframe)) = frames.pop() {
+            if let Some(wallclock) = clock.wallclock(sample.packet_timestamp, clock_rate) {
+                recording.write_frame(track, wallclock, keyframe, &sample.data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wants_keyframe(&self) -> bool {
+        match self {
+            Output::Raw(_) => false,
+            Output::Webm { recording, .. } => recording.lock().unwrap().wants_keyframe(),
+        }
+    }
+
+    fn close(self, path: &Path) {
+        match self {
+            Output::Raw(mut writer) => {
+                log::info!("⏹ Finished recording {}", path.display());
+                if let Err(err) = writer.close() {
+                    log::warn!("Failed to finish recording {}: {}", path.display(), err);
+                }
+            }
+            // The file is finished once its last track is done
+            Output::Webm { recording, track, .. } => recording.lock().unwrap().close_track(track),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TrackKind {
+    Video,
+    Audio { sample_rate: u32, channels: u16 },
+}
+
+#[derive(Debug)]
+struct RecordedTrack {
+    kind: TrackKind,
+    // Whether a sender report has arrived, so the track's frames can be timed
+    synced: bool,
+    closed: bool,
+}
+
+enum WebmState {
+    // Tracks can still be added. The file starts once every track is synced, on a keyframe
+    // when there's video.
+    Waiting(BufWriter<File>),
+    Writing {
+        writer: WebmWriter<BufWriter<File>>,
+        // The publisher's wallclock at the start of the file, in nanoseconds
+        start: i64,
+    },
+    Finished,
+}
+
+// A participant's camera and microphone muxed into one WebM file, timed by the publisher's sender
+// reports so they play back in sync. Frames from before the file starts are dropped.
+pub struct WebmRecording {
+    pub path: PathBuf,
+    tracks: Vec<RecordedTrack>,
+    state: WebmState,
+}
+
+impl std::fmt::Debug for WebmRecording {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebmRecording").field("path", &self.path).field("tracks", &self.tracks).finish()
+    }
+}
+
+impl WebmRecording {
+    pub fn create(dir: &Path, room: &str, publisher: &str) -> Result<Self> {
+        let path = recording_path(dir, room, publisher, None, "webm")?;
+        let file = BufWriter::new(File::create(&path)?);
+
+        Ok(WebmRecording {
+            path,
+            tracks: vec![],
+            state: WebmState::Waiting(file),
+        })
+    }
+
+    // Whether a track of this kind can still be added
+    pub fn accepts(&self, video: bool) -> bool {
+        matches!(self.state, WebmState::Waiting(_))
+            && !self.tracks.iter().any(|t| (t.kind == TrackKind::Video) == video)
+    }
+
+    fn add_track(&mut self, kind: TrackKind) -> Option<usize> {
+        if !self.accepts(kind == TrackKind::Video) {
+            return None;
+        }
+
+        self.tracks.push(RecordedTrack { kind, synced: false, closed: false });
+        Some(self.tracks.len() - 1)
+    }
+
+    fn has_video(&self) -> bool {
+        self.tracks.iter().any(|t| t.kind == TrackKind::Video)
+    }
+
+    fn wants_keyframe(&self) -> bool {
+        matches!(self.state, WebmState::Waiting(_))
+            && self.has_video()
+            && self.tracks.iter().all(|t| t.synced)
+    }
+
+    fn write_frame(&mut self, track: usize, wallclock: i64, keyframe: bool, data: &[u8]) -> Result<()> {
+        if let WebmState::Waiting(_) = self.state {
+            let video = self.tracks[track].kind == TrackKind::Video;
+            if !self.tracks.iter().all(|t| t.synced) || (self.has_video() && !(video && keyframe)) {
+                return Ok(());
+            }
+            self.start(wallclock, data)?;
+        }
+
+        if let WebmState::Writing { writer, start } = &mut self.state {
+            // Audio from just before the first keyframe
+            if wallclock < *start {
+                return Ok(());
+            }
+            writer.write_frame(track as u64 + 1, ((wallclock - *start) / 1_000_000) as u64, keyframe, data)?;
+        }
+
+        Ok(())
+    }
+
+    // Write the file's header, sizing the video from the keyframe it starts on
+    fn start(&mut self, wallclock: i64, keyframe: &[u8]) -> Result<()> {
+        let (width, height) = vp8_dimensions(keyframe).unwrap_or((640, 480));
+        let tracks: Vec<WebmTrack> = self.tracks
+            .iter()
+            .map(|t| match t.kind {
+                TrackKind::Video => WebmTrack::Vp8 { width, height },
+                TrackKind::Audio { sample_rate, channels } => WebmTrack::Opus { sample_rate, channels },
+            })
+            .collect();
+
+        if let WebmState::Waiting(file) = std::mem::replace(&mut self.state, WebmState::Finished) {
+            log::info!("⏺ Started writing {}", self.path.display());
+            self.state = WebmState::Writing {
+                writer: WebmWriter::new(file, &tracks)?,
+                start: wallclock,
+            };
+        }
+        Ok(())
+    }
+
+    fn close_track(&mut self, track: usize) {
+        self.tracks[track].closed = true;
+        if !self.tracks.iter().all(|t| t.closed) {
+            return;
+        }
+
+        match std::mem::replace(&mut self.state, WebmState::Finished) {
+            WebmState::Writing { writer, .. } => match writer.finish() {
+                Ok(_) => log::info!("⏹ Finished recording {}", self.path.display()),
+                Err(err) => log::warn!("Failed to finish recording {}: {}", self.path.display(), err),
+            },
+            WebmState::Waiting(file) => {
+                drop(file);
+                log::info!("Nothing was recorded to {}, removing it", self.path.display());
+                let _ = std::fs::remove_file(&self.path);
+            }
+            WebmState::Finished => {}
+        }
+    }
+}
+
+// The size of a VP8 keyframe, from its header
+fn vp8_dimensions(frame: &[u8]) -> Option<(u16, u16)> {
+    if frame.len() < 10 || frame[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+    Some((width, height))
+}
+
+// Recordings are named by room, publisher, start time and, for files of a single track, the
+// track id
+fn recording_path(dir: &Path, room: &str, publisher: &str, track_id: Option<&str>, extension: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut name = format!("{}_{}_{}", sanitize(room), sanitize(publisher), Utc::now().format("%Y%m%dT%H%M%SZ"));
+    if let Some(track_id) = track_id {
+        name.push('_');
+        name.push_str(&sanitize(track_id));
+    }
+    Ok(dir.join(format!("{}.{}", name, extension)))
+}
+
+// Room names, uuids and track ids all come from clients, so they're kept from escaping the
+// recording directory
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 100.5 seconds past the NTP epoch, in nanoseconds
+    const REPORT_NS: i64 = 100_500_000_000;
+
+    fn clock(rtp_time: u32) -> SenderClock {
+        let clock = SenderClock::default();
+        clock.update(&SenderReport {
+            ntp_time: (100 << 32) | (1 << 31),
+            rtp_time,
+            ..Default::default()
+        });
+        clock
+    }
+
+    #[test]
+    fn no_wallclock_before_a_sender_report() {
+        assert_eq!(SenderClock::default().wallclock(1000, VP8_CLOCK_RATE), None);
+    }
+
+    #[test]
+    fn wallclock_counts_from_the_report() {
+        let clock = clock(1000);
+        assert_eq!(clock.wallclock(1000, VP8_CLOCK_RATE), Some(REPORT_NS));
+        assert_eq!(clock.wallclock(1000 + 90000, VP8_CLOCK_RATE), Some(REPORT_NS + 1_000_000_000));
+        assert_eq!(clock.wallclock(1000 + 960, OPUS_CLOCK_RATE), Some(REPORT_NS + 20_000_000));
+        // Packets from before the report
+        assert_eq!(clock.wallclock(1000u32.wrapping_sub(45000), VP8_CLOCK_RATE), Some(REPORT_NS - 500_000_000));
+    }
+
+    #[test]
+    fn wallclock_follows_rtp_timestamps_across_wraparound() {
+        let clock = clock(u32::MAX - 44999);
+        assert_eq!(clock.wallclock(45000, VP8_CLOCK_RATE), Some(REPORT_NS + 1_000_000_000));
+        assert_eq!(clock.wallclock(u32::MAX - 89999, VP8_CLOCK_RATE), Some(REPORT_NS - 500_000_000));
+    }
+
+    #[test]
+    fn a_newer_report_replaces_the_last() {
+        let clock = clock(1000);
+        clock.update(&SenderReport {
+            ntp_time: 200 << 32,
+            rtp_time: 5000,
+            ..Default::default()
+        });
+        assert_eq!(clock.wallclock(5000 + 45000, VP8_CLOCK_RATE), Some(200_500_000_000));
+    }
+}
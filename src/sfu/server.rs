@@ -0,0 +1,331 @@
+use anyhow::{anyhow, Result};
+use flume::{Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use crate::sfu::auth::{Authenticator, Permissions};
+use crate::sfu::config::Config;
+use crate::sfu::egress::RtpEgress;
+use crate::sfu::ingest::{self, RtpIngest};
+use crate::sfu::media::{PeerKind, Router};
+use crate::sfu::playback::{self, Playback};
+use crate::sfu::signal::{self, SocketMessage, WebSocketTransport};
+use crate::sfu::transport::{serve_connection, Transport};
+use crate::PeerChanCommand;
+
+// How many events a slow listener can fall behind by before it starts missing them
+const EVENT_CAPACITY: usize = 256;
+
+// How much of the drain timeout is kept back for closing the connections left after draining,
+// so the server exits within the drain timeout however slow the close is
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Things happening across every room, for services embedding the SFU to follow along.
+#[derive(Debug, Clone)]
+pub enum SfuEvent {
+    PeerJoined {
+        uuid: String,
+        room: String,
+        name: String,
+    },
+    PeerLeft {
+        uuid: String,
+        room: String,
+    },
+    TrackPublished {
+        room: String,
+        publisher: String,
+        track_id: String,
+        kind: String,
+    },
+    TrackUnpublished {
+        room: String,
+        publisher: String,
+        track_id: String,
+    },
+    // The last peer left the room, or it was closed
+    RoomClosed {
+        room: String,
+    },
+    RecordingStarted {
+        room: String,
+    },
+    RecordingStopped {
+        room: String,
+    },
+}
+
+// Sets up an SFU: the router task, and the transports clients connect over. By default that's
+// the HTTP server for the websocket, WHIP and WHEP endpoints.
+//
+//   let server = SfuServer::builder(config).build().await?;
+//   let handle = server.handle();
+//   let events = handle.events();
+pub struct SfuServerBuilder {
+    config: Config,
+    serve_http: bool,
+    transports: Vec<Box<dyn Transport>>,
+}
+
+impl SfuServerBuilder {
+    pub fn new(config: Config) -> Self {
+        SfuServerBuilder {
+            config,
+            serve_http: true,
+            transports: vec![],
+        }
+    }
+
+    // Whether to serve the websocket, WHIP and WHEP endpoints on the config's listen address.
+    // Services bringing their own signaling can turn this off and drive peers through the handle.
+    pub fn serve_http(mut self, serve_http: bool) -> Self {
+        self.serve_http = serve_http;
+        self
+    }
+
+    // Accept clients over another transport as well, like an in-process ChannelTransport
+    pub fn transport(mut self, transport: impl Transport) -> Self {
+        self.transports.push(Box::new(transport));
+        self
+    }
+
+    pub async fn build(self) -> Result<SfuServer> {
+        let config = Arc::new(self.config);
+        let (peer_chan_tx, peer_chan_rx) = flume::unbounded::<PeerChanCommand>();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let shutdown = Shutdown(shutdown_rx);
+
+        let auth = Authenticator::from_config(&config)?.map(Arc::new);
+        if auth.is_none() {
+            log::warn!("⚠️ No JWT key configured, anyone can connect and publish");
+        }
+
+        let router = Router::new(Arc::clone(&config), peer_chan_tx.clone(), events.clone()).await?;
+
+        log::debug!("Creating peer channel listener.");
+        let router = tokio::spawn(router.run(peer_chan_rx));
+
+        let mut transports = self.transports;
+        if self.serve_http {
+            transports.insert(0, Box::new(WebSocketTransport { addr: config.listen, auth }));
+        }
+
+        for transport in transports {
+            let new_conn_rx = transport.start(peer_chan_tx.clone(), shutdown.clone())?;
+
+            let tx = peer_chan_tx.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                while let Ok(conn) = new_conn_rx.recv_async().await {
+                    if shutdown.is_draining() {
+                        let _ = conn.tx.send(SocketMessage::error("server is shutting down"));
+                        continue;
+                    }
+                    serve_connection(conn, tx.clone());
+                }
+            });
+        }
+
+        let handle = SfuHandle { peer_chan_tx, events };
+        for rtp_ingest in config.rtp_ingest.iter().cloned() {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let room = rtp_ingest.room.to_owned();
+                if let Err(err) = handle.ingest(rtp_ingest).await {
+                    log::warn!("Failed to start RTP ingest into room {}: {}", room, err);
+                }
+            });
+        }
+
+        Ok(SfuServer {
+            handle,
+            router,
+            shutdown_tx,
+            drain_timeout: config.drain_timeout(),
+        })
+    }
+}
+
+// A running SFU. Dropping it leaves the router running, use run to wait on it.
+pub struct SfuServer {
+    handle: SfuHandle,
+    router: JoinHandle<()>,
+    shutdown_tx: watch::Sender<bool>,
+    drain_timeout: Duration,
+}
+
+impl SfuServer {
+    pub fn builder(config: Config) -> SfuServerBuilder {
+        SfuServerBuilder::new(config)
+    }
+
+    pub fn handle(&self) -> SfuHandle {
+        self.handle.clone()
+    }
+
+    // Run until the router task finishes, or until a SIGTERM or ctrl-c, which shuts the server
+    // down gracefully.
+    pub async fn run(mut self) -> Result<()> {
+        tokio::select! {
+            result = &mut self.router => {
+                result?;
+                return Ok(());
+            }
+            result = shutdown_signal() => result?,
+        }
+
+        self.shutdown().await
+    }
+
+    // Stop taking new clients, tell every peer we're going away and give them most of the drain
+    // timeout to leave, then close whatever connections are left in the rest of it.
+    pub async fn shutdown(self) -> Result<()> {
+        let close_timeout = CLOSE_TIMEOUT.min(self.drain_timeout);
+        let leave_timeout = self.drain_timeout - close_timeout;
+        log::info!("🛑 Shutting down, giving peers {:?} to leave", leave_timeout);
+        let _ = self.shutdown_tx.send(true);
+
+        let (done_tx, done_rx) = flume::bounded::<()>(1);
+        self.handle.command(PeerChanCommand::Drain { done: done_tx })?;
+        if tokio::time::timeout(leave_timeout, done_rx.recv_async()).await.is_ok() {
+            log::info!("Every peer left, exiting.");
+            return Ok(());
+        }
+
+        let (done_tx, done_rx) = flume::bounded::<()>(1);
+        self.handle.command(PeerChanCommand::CloseAll { done: done_tx })?;
+        if tokio::time::timeout(close_timeout, done_rx.recv_async()).await.is_err() {
+            log::warn!("Timed out closing peer connections, exiting anyway.");
+        }
+
+        Ok(())
+    }
+}
+
+// Resolves on the first SIGTERM or ctrl-c
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => log::info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => result?,
+    }
+
+    Ok(())
+}
+
+// Whether the server is shutting down, shared with the transports so they stop taking clients
+// and with the readiness check.
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn is_draining(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    // Resolves once shutdown starts. Never resolves if the server was dropped without one.
+    pub async fn wait(mut self) {
+        while !*self.0.borrow() {
+            if self.0.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+// Issues commands to a running SFU's router. Cheap to clone, and usable from any task.
+#[derive(Debug, Clone)]
+pub struct SfuHandle {
+    peer_chan_tx: Sender<PeerChanCommand>,
+    events: broadcast::Sender<SfuEvent>,
+}
+
+impl SfuHandle {
+    // Send any command to the router
+    pub fn command(&self, cmd: PeerChanCommand) -> Result<()> {
+        self.peer_chan_tx.send(cmd)?;
+        Ok(())
+    }
+
+    // Join a peer to a room with its offer. The answer, candidates and room events for the peer
+    // arrive on the returned channel, as they would on its websocket.
+    pub fn join(&self, uuid: &str, room: &str, name: &str, kind: PeerKind, offer: RTCSessionDescription) -> Result<Receiver<SocketMessage>> {
+        let (tx, rx) = flume::unbounded::<SocketMessage>();
+
+        self.command(PeerChanCommand::ReceiveOffer {
+            uuid: uuid.to_owned(),
+            room: room.to_owned(),
+            name: name.to_owned(),
+            sdp: offer,
+            sources: HashMap::new(),
+            tx: Arc::new(tx),
+            kind,
+            permissions: Permissions::all(),
+        })?;
+
+        Ok(rx)
+    }
+
+    pub fn leave(&self, uuid: &str) -> Result<()> {
+        self.command(PeerChanCommand::PeerLeft { uuid: uuid.to_owned() })
+    }
+
+    pub fn close_room(&self, room: &str) -> Result<()> {
+        self.command(PeerChanCommand::CloseRoom { room: room.to_owned() })
+    }
+
+    pub fn start_recording(&self, room: &str) -> Result<()> {
+        self.command(PeerChanCommand::StartRecording { room: room.to_owned() })
+    }
+
+    pub fn stop_recording(&self, room: &str) -> Result<()> {
+        self.command(PeerChanCommand::StopRecording { room: room.to_owned() })
+    }
+
+    // Send a published track to a UDP destination as plain RTP. Returns the egress's id, for
+    // stopping it, and the SDP a consumer like ffmpeg opens to receive it.
+    pub async fn start_egress(&self, egress: RtpEgress) -> Result<(String, String)> {
+        let id = signal::uuid();
+        let (done_tx, done_rx) = flume::bounded(1);
+        self.command(PeerChanCommand::StartEgress {
+            id: id.to_owned(),
+            egress,
+            done: done_tx,
+        })?;
+
+        let sdp = done_rx.recv_async().await?.map_err(|e| anyhow!(e))?;
+        Ok((id, sdp))
+    }
+
+    pub fn stop_egress(&self, id: &str) -> Result<()> {
+        self.command(PeerChanCommand::StopEgress { id: id.to_owned() })
+    }
+
+    // Join a virtual participant to a room that plays files into it, returning its uuid. It
+    // leaves by itself once the files end, unless they loop, or when passed to leave.
+    pub async fn play(&self, playback: Playback) -> Result<String> {
+        let uuid = signal::uuid();
+        playback::start(self, &uuid, playback).await?;
+        Ok(uuid)
+    }
+
+    // Publish plain RTP arriving on local UDP ports into a room, returning the publisher's uuid.
+    // The ports stay bound until it's passed to leave.
+    pub async fn ingest(&self, ingest: RtpIngest) -> Result<String> {
+        let uuid = signal::uuid();
+        ingest::start(self, &uuid, ingest).await?;
+        Ok(uuid)
+    }
+
+    // Follow events from every room. Each call gets its own stream, starting from now.
+    pub fn events(&self) -> broadcast::Receiver<SfuEvent> {
+        self.events.subscribe()
+    }
+}
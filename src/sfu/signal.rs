@@ -1,7 +1,7 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use std::net::SocketAddr;
 use flume::Receiver;
 use flume::Sender;
@@ -11,56 +11,225 @@ use std::convert::Infallible;
 use tungstenite::Message;
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use crate::sfu::media::TrackSource;
+use crate::sfu::simulcast::Layer;
+use crate::sfu::transport::{Connection, Transport};
+use std::sync::Arc;
+use std::collections::HashMap;
+use crate::sfu::auth::{Authenticator, Claims};
+use crate::sfu::health::{self, HEALTH_PATH, READY_PATH};
+use crate::sfu::metrics::{self, METRICS_PATH};
+use crate::sfu::server::Shutdown;
+use crate::sfu::whep::WHEP_PATH;
+use crate::sfu::whip::{is_under, text, WHIP_PATH};
+use crate::PeerChanCommand;
 
-fn uuid() -> String {
+// A fresh id for a peer
+pub fn uuid() -> String {
     Uuid::new_v4().to_hyphenated().to_string()
 }
 
+// Bumped whenever a change to SocketMessage would break existing clients
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Messages exchanged with clients over the websocket, tagged by their `event` field.
+// The server opens with a Welcome carrying the uuid it assigned the connection, which the client
+// must use in its messages. A client must send a Join before its first offer.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SocketMessage {
-    pub event: String,
-    pub data: String,
-    pub uuid: String,
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SocketMessage {
+    Welcome {
+        uuid: String,
+        version: u32,
+    },
+    Join {
+        version: u32,
+        uuid: String,
+        room: String,
+        // The display name shown to the rest of the room
+        #[serde(default)]
+        name: String,
+    },
+    Offer {
+        uuid: String,
+        sdp: RTCSessionDescription,
+        // What each track in the offer carries, keyed by track id. Tracks left out are assumed
+        // to be the camera or microphone.
+        #[serde(default)]
+        sources: HashMap<String, TrackSource>,
+    },
+    Answer {
+        uuid: String,
+        sdp: RTCSessionDescription,
+    },
+    Candidate {
+        uuid: String,
+        candidate: RTCIceCandidateInit,
+    },
+    // Disconnect everyone in a room, for clients with the admin permission
+    CloseRoom {
+        room: String,
+    },
+    // Sent to everyone in a room that was closed. They've been removed from it, and have to join
+    // again to get back in.
+    RoomClosed {
+        room: String,
+    },
+    // Record a room, or stop recording it, for clients with the admin permission
+    StartRecording {
+        room: String,
+    },
+    StopRecording {
+        room: String,
+    },
+    // Sent to everyone in a room when recording starts or stops
+    Recording {
+        active: bool,
+    },
+    // Send a published track to a UDP destination as plain RTP, for clients with the admin
+    // permission. Answered with the egress's id and the SDP a consumer like ffmpeg opens.
+    StartEgress {
+        room: String,
+        publisher: String,
+        track_id: String,
+        // Where to send the RTP, like "127.0.0.1:5004"
+        dest: SocketAddr,
+        // Any dynamic payload type, 96 to 127
+        payload_type: u8,
+    },
+    EgressStarted {
+        id: String,
+        sdp: String,
+    },
+    StopEgress {
+        id: String,
+    },
+    // Pick the simulcast layer of a publisher's track that this peer receives
+    SelectLayer {
+        uuid: String,
+        publisher: String,
+        track_id: String,
+        layer: Layer,
+    },
+    Leave {
+        uuid: String,
+    },
+    // Roster events, sent by the server as peers come and go and publish tracks. A track's output
+    // on the client has the track id and stream id given here.
+    ParticipantJoined {
+        uuid: String,
+        name: String,
+    },
+    ParticipantLeft {
+        uuid: String,
+    },
+    TrackPublished {
+        publisher: String,
+        track_id: String,
+        stream_id: String,
+        kind: String,
+        source: TrackSource,
+    },
+    TrackUnpublished {
+        publisher: String,
+        track_id: String,
+    },
+    // The server is going away. The client should leave and reconnect, to another instance if
+    // it's behind a load balancer, before it's disconnected.
+    Shutdown {},
+    // Sent to the client when one of its messages couldn't be handled
+    Error {
+        message: String,
+    },
 }
 
-type Connection = (String, Sender<SocketMessage>, Receiver<SocketMessage>);
+impl SocketMessage {
+    pub fn error(message: impl Into<String>) -> Self {
+        SocketMessage::Error { message: message.into() }
+    }
 
-pub async fn ws_sdp_signaler(port: u16) -> Receiver<Connection> {
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    // The peer a message from a client claims to be from
+    pub fn uuid(&self) -> Option<&str> {
+        match self {
+            SocketMessage::Join { uuid, .. }
+            | SocketMessage::Offer { uuid, .. }
+            | SocketMessage::Answer { uuid, .. }
+            | SocketMessage::Candidate { uuid, .. }
+            | SocketMessage::SelectLayer { uuid, .. }
+            | SocketMessage::Leave { uuid } => Some(uuid),
+            _ => None,
+        }
+    }
+}
 
+// Serves websocket clients, and the WHIP and WHEP endpoints, over HTTP.
+#[derive(Debug)]
+pub struct WebSocketTransport {
+    pub addr: SocketAddr,
+    // Checks client tokens, when authentication is turned on
+    pub auth: Option<Arc<Authenticator>>,
+}
+
+impl Transport for WebSocketTransport {
+    fn start(self: Box<Self>, peer_chan_tx: Sender<PeerChanCommand>, shutdown: Shutdown) -> Result<Receiver<Connection>> {
+        log::info!("Listening on {}", self.addr);
+        ws_sdp_signaler(self.addr, peer_chan_tx, shutdown, self.auth)
+    }
+}
+
+pub fn ws_sdp_signaler(
+    addr: SocketAddr,
+    peer_chan_tx: Sender<PeerChanCommand>,
+    shutdown: Shutdown,
+    auth: Option<Arc<Authenticator>>,
+) -> Result<Receiver<Connection>> {
     // A channel for passing new connections (which themselves contain channels) to the main task
     let (conn_chan_tx, conn_chan_rx) = flume::unbounded::<Connection>();
     let (conn_chan_2_tx, conn_chan_2_rx) = flume::unbounded::<Connection>();
 
-    let mut connections: i32 = 0;
     tokio::spawn(async move {
-        println!("Creating connections passer");
-        while let Ok(channels) = conn_chan_rx.recv() {
-            println!("Got new connection, length is: {:?}", connections);
-            connections = connections + 1;
+        log::debug!("Creating connections passer");
+        while let Ok(channels) = conn_chan_rx.recv_async().await {
+            metrics::WEBSOCKETS_TOTAL.inc();
+            log::debug!("Got new connection, length is: {:?}", metrics::WEBSOCKETS_TOTAL.get());
             conn_chan_2_tx.send(channels).unwrap();
         }
     });
 
+    let shutdown_clone = shutdown.clone();
     let make_svc = make_service_fn(move |_conn: &AddrStream| {
         let conn_chan_tx_clone = conn_chan_tx.clone();
+        let peer_chan_tx_clone = peer_chan_tx.clone();
+        let shutdown_clone = shutdown_clone.clone();
+        let auth_clone = auth.clone();
 
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(req, uuid(), conn_chan_tx_clone.clone())
+                handle_request(
+                    req,
+                    uuid(),
+                    conn_chan_tx_clone.clone(),
+                    peer_chan_tx_clone.clone(),
+                    shutdown_clone.clone(),
+                    auth_clone.clone(),
+                )
             }))
         }
     });
 
-    let server = Server::bind(&addr).serve(make_svc);
+    // Keeps serving while the server drains, so health checks and sessions ending still get
+    // answers. New clients are turned away in handle_request.
+    let server = Server::try_bind(&addr)?.serve(make_svc);
 
     tokio::spawn(async move {
         if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
+            log::error!("server error: {}", e);
         }
     });
 
-    conn_chan_2_rx
+    Ok(conn_chan_2_rx)
 }
 
 /// Handle a HTTP or WebSocket request.
@@ -68,20 +237,56 @@ async fn handle_request(
     request: Request<Body>,
     uuid: String,
     conn_tx: Sender<Connection>,
+    peer_chan_tx: Sender<PeerChanCommand>,
+    shutdown: Shutdown,
+    auth: Option<Arc<Authenticator>>,
 ) -> Result<Response<Body>, anyhow::Error> {
+    // New websockets and WHIP or WHEP sessions go to another instance once shutdown starts
+    let path = request.uri().path().to_owned();
+    let http_session = is_under(&path, WHIP_PATH) || is_under(&path, WHEP_PATH);
+    let joining = hyper_tungstenite::is_upgrade_request(&request)
+        || (request.method() == Method::POST && http_session);
+    if joining && shutdown.is_draining() {
+        return text(StatusCode::SERVICE_UNAVAILABLE, "server is shutting down".to_owned());
+    }
+
+    // Websockets, WHIP and WHEP need a valid token once authentication is turned on
+    let needs_auth = hyper_tungstenite::is_upgrade_request(&request) || http_session;
+
+    let identity = match &auth {
+        Some(auth) if needs_auth => match auth.authenticate(&request) {
+            Ok(claims) => Some(claims),
+            Err(e) => {
+                log::warn!("Rejecting unauthenticated request to {}: {}", request.uri().path(), e);
+                return text(StatusCode::UNAUTHORIZED, format!("{}", e));
+            }
+        },
+        _ => None,
+    };
+
     // Check if the request is a websocket upgrade request.
     if hyper_tungstenite::is_upgrade_request(&request) {
         let (response, websocket) = hyper_tungstenite::upgrade(request, None)?;
 
         // Spawn a task to handle the websocket connection.
         tokio::spawn(async move {
-            if let Err(e) = serve_websocket(websocket, uuid, conn_tx).await {
-                eprintln!("Error in websocket connection: {}", e);
+            if let Err(e) = serve_websocket(websocket, uuid, conn_tx, identity).await {
+                log::warn!("Error in websocket connection: {}", e);
             }
         });
 
         // Return the response so the spawned future can continue.
         Ok(response)
+    } else if request.uri().path() == HEALTH_PATH {
+        health::handle_health()
+    } else if request.uri().path() == READY_PATH {
+        health::handle_ready(&shutdown)
+    } else if request.uri().path() == METRICS_PATH {
+        metrics::handle_metrics(&peer_chan_tx)
+    } else if is_under(&path, WHIP_PATH) {
+        crate::sfu::whip::handle_whip(request, uuid, peer_chan_tx, identity).await
+    } else if is_under(&path, WHEP_PATH) {
+        crate::sfu::whep::handle_whep(request, uuid, peer_chan_tx, identity).await
     } else {
         // Handle regular HTTP requests here.
         Ok(Response::new(Body::from("Hello HTTP!")))
@@ -93,18 +298,28 @@ async fn serve_websocket(
     websocket: HyperWebsocket,
     uuid: String,
     conn_tx: Sender<Connection>,
+    identity: Option<Claims>,
 ) -> Result<(), anyhow::Error> {
     let (out_tx, out_rx) = flume::unbounded::<SocketMessage>();
     let (in_tx, in_rx) = flume::unbounded::<SocketMessage>();
 
     let (mut sink, mut stream) = websocket.await?.split();
+    let _open = metrics::GaugeGuard::new(&metrics::WEBSOCKETS);
 
-    conn_tx.send((uuid.to_owned(), out_tx, in_rx)).unwrap();
+    conn_tx.send(Connection {
+        id: uuid.to_owned(),
+        tx: Arc::new(out_tx.clone()),
+        rx: in_rx,
+        identity,
+    }).map_err(|_| anyhow!("server stopped taking connections"))?;
 
     tokio::spawn(async move {
         while let Ok(message) = out_rx.recv_async().await {
             // println!("Trying to send outbound ws message: {:?}", message);
-            sink.send(Message::text(serde_json::to_string(&message).unwrap())).await.unwrap();
+            if let Err(e) = sink.send(Message::text(serde_json::to_string(&message).unwrap())).await {
+                log::warn!("Couldn't send ws message, closing the writer: {}", e);
+                break;
+            }
         }
     });
 
@@ -112,16 +327,30 @@ async fn serve_websocket(
         // println!("Received incoming ws message: {:?}", message);
         match message? {
             Message::Text(msg) => {
-                in_tx.send(serde_json::from_str(&msg).unwrap()).unwrap();
+                match serde_json::from_str::<SocketMessage>(&msg) {
+                    Ok(message) => {
+                        if in_tx.send(message).is_err() {
+                            log::warn!("Connection {} stopped taking messages, closing the websocket", uuid);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Dropping malformed ws message: {}", e);
+                        if out_tx.send(SocketMessage::error(format!("malformed message: {}", e))).is_err() {
+                            log::warn!("Writer for {} has stopped, closing the websocket", uuid);
+                            break;
+                        }
+                    }
+                }
             }
             Message::Close(msg) => {
                 if let Some(msg) = &msg {
-                    println!(
+                    log::debug!(
                         "Received close message with code {} and message: {}",
                         msg.code, msg.reason
                     );
                 } else {
-                    println!("Received close message");
+                    log::debug!("Received close message");
                 }
             }
             _ => (),
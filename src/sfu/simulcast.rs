@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use webrtc::rtp::packet::Packet;
+use webrtc::track::track_local::TrackLocalWriter;
+
+// The quality a subscriber wants from a simulcast track. Publishers name their layers with
+// arbitrary rids, so layers are ranked by how much data they carry instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    Low,
+    Mid,
+    High,
+}
+
+impl Layer {
+    // Pick this layer out of rids ordered from lowest to highest quality
+    pub fn pick<'a>(&self, ranked: &'a [String]) -> Option<&'a String> {
+        match self {
+            Layer::Low => ranked.first(),
+            Layer::Mid => ranked.get(ranked.len() / 2),
+            Layer::High => ranked.last(),
+        }
+    }
+}
+
+// Feeds a subscriber's output track from one layer of a published track at a time. Switching
+// layers waits for a keyframe on the new layer, and rewrites sequence numbers and timestamps so
+// the subscriber sees one continuous stream. The output track itself stamps its own SSRC on
+// every packet it writes. That's a subscriber's output track, or a UDP sink for an RTP egress.
+#[derive(Debug)]
+pub struct Forwarder {
+    pub track: Arc<dyn TrackLocalWriter + Send + Sync>,
+    pub preferred: Layer,
+    // The rid being forwarded, None until the first packet has been sent
+    pub current: Option<String>,
+    // The rid to switch to at its next keyframe
+    pub target: String,
+    // Audio can switch at any packet, video only at a keyframe
+    waits_for_keyframe: bool,
+    // Gap left between the last timestamp sent and the first one from a new layer
+    frame_duration: u32,
+    seq_offset: u16,
+    ts_offset: u32,
+    last_seq: u16,
+    last_ts: u32,
+}
+
+impl Forwarder {
+    pub fn new(track: Arc<dyn TrackLocalWriter + Send + Sync>, target: String, waits_for_keyframe: bool, clock_rate: u32) -> Self {
+        Forwarder {
+            track,
+            preferred: Layer::High,
+            current: None,
+            target,
+            waits_for_keyframe,
+            frame_duration: clock_rate / 30,
+            seq_offset: 0,
+            ts_offset: 0,
+            last_seq: 0,
+            last_ts: 0,
+        }
+    }
+
+    // Whether the forwarder is waiting on a keyframe from its target layer
+    pub fn is_switching(&self) -> bool {
+        self.current.as_deref() != Some(self.target.as_str())
+    }
+
+    // Rewrite a packet from the given layer for the subscriber, or None if it should be dropped.
+    pub fn rewrite(&mut self, rid: &str, packet: &Packet) -> Option<Packet> {
+        if self.current.as_deref() != Some(rid) {
+            if rid != self.target || (self.waits_for_keyframe && !is_vp8_keyframe(&packet.payload)) {
+                return None;
+            }
+
+            // Carry on from the last packet sent on the previous layer
+            if self.current.is_some() {
+                self.seq_offset = self.last_seq.wrapping_add(1).wrapping_sub(packet.header.sequence_number);
+                self.ts_offset = self.last_ts.wrapping_add(self.frame_duration).wrapping_sub(packet.header.timestamp);
+            }
+            self.current = Some(rid.to_owned());
+        }
+
+        let mut packet = packet.clone();
+        packet.header.sequence_number = packet.header.sequence_number.wrapping_add(self.seq_offset);
+        packet.header.timestamp = packet.header.timestamp.wrapping_add(self.ts_offset);
+
+        self.last_seq = packet.header.sequence_number;
+        self.last_ts = packet.header.timestamp;
+
+        Some(packet)
+    }
+}
+
+// Whether the packet carries the start of a VP8 keyframe, per the payload descriptor in
+// RFC 7741 section 4.2 and the frame tag in RFC 6386 section 9.1.
+pub fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    if payload.is_empty() {
+        return false;
+    }
+
+    let x = payload[0] & 0x80 != 0;
+    let s = payload[0] & 0x10 != 0;
+    let pid = payload[0] & 0x07;
+
+    // Only the first packet of the first partition carries the frame tag
+    if !s || pid != 0 {
+        return false;
+    }
+
+    let mut i = 1;
+    if x {
+        let ext = match payload.get(i) {
+            Some(&ext) => ext,
+            None => return false,
+        };
+        i += 1;
+        // Picture ID, one byte or two if the M bit is set
+        if ext & 0x80 != 0 {
+            match payload.get(i) {
+                Some(&picture_id) => i += if picture_id & 0x80 != 0 { 2 } else { 1 },
+                None => return false,
+            }
+        }
+        // TL0PICIDX
+        if ext & 0x40 != 0 {
+            i += 1;
+        }
+        // TID/Y/KEYIDX
+        if ext & 0x30 != 0 {
+            i += 1;
+        }
+    }
+
+    // The P bit of the frame tag is clear on keyframes
+    payload.get(i).map_or(false, |tag| tag & 0x01 == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use webrtc::rtp::header::Header;
+    use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+    use crate::sfu::local::{opus_codec, vp8_codec};
+
+    // The smallest VP8 payloads starting a keyframe and a delta frame
+    const KEYFRAME: &[u8] = &[0x10, 0x00];
+    const DELTA: &[u8] = &[0x10, 0x01];
+
+    fn forwarder(target: &str, video: bool) -> Forwarder {
+        let (codec, clock_rate) = if video { (vp8_codec(), 90000) } else { (opus_codec(), 48000) };
+        let track = Arc::new(TrackLocalStaticRTP::new(codec, "track".to_owned(), "stream".to_owned()));
+        Forwarder::new(track, target.to_owned(), video, clock_rate)
+    }
+
+    fn packet(sequence_number: u16, timestamp: u32, payload: &'static [u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    // The sequence number and timestamp of a rewritten packet, None if it was dropped
+    fn forward(forwarder: &mut Forwarder, rid: &str, packet: &Packet) -> Option<(u16, u32)> {
+        forwarder.rewrite(rid, packet).map(|rewritten| {
+            assert_eq!(rewritten.payload, packet.payload);
+            (rewritten.header.sequence_number, rewritten.header.timestamp)
+        })
+    }
+
+    #[test]
+    fn video_starts_on_a_keyframe_from_the_target() {
+        let mut forwarder = forwarder("h", true);
+        assert!(forwarder.is_switching());
+
+        assert_eq!(forward(&mut forwarder, "h", &packet(6, 0, DELTA)), None);
+        assert_eq!(forward(&mut forwarder, "l", &packet(40, 0, KEYFRAME)), None);
+        // The first layer is forwarded as it comes
+        assert_eq!(forward(&mut forwarder, "h", &packet(7, 500, KEYFRAME)), Some((7, 500)));
+        assert_eq!(forward(&mut forwarder, "h", &packet(8, 3500, DELTA)), Some((8, 3500)));
+        assert!(!forwarder.is_switching());
+    }
+
+    #[test]
+    fn layer_switch_continues_the_stream() {
+        let mut forwarder = forwarder("h", true);
+        assert_eq!(forward(&mut forwarder, "h", &packet(100, 1000, KEYFRAME)), Some((100, 1000)));
+        assert_eq!(forward(&mut forwarder, "h", &packet(101, 4000, DELTA)), Some((101, 4000)));
+
+        // The old layer carries on until the new one has a keyframe
+        forwarder.target = "l".to_owned();
+        assert!(forwarder.is_switching());
+        assert_eq!(forward(&mut forwarder, "h", &packet(102, 7000, DELTA)), Some((102, 7000)));
+        assert_eq!(forward(&mut forwarder, "l", &packet(5000, 87000, DELTA)), None);
+
+        // Sequence numbers pick up after the last one sent, and the timestamp a frame after it
+        assert_eq!(forward(&mut forwarder, "l", &packet(5001, 90000, KEYFRAME)), Some((103, 10000)));
+        assert!(!forwarder.is_switching());
+        assert_eq!(forward(&mut forwarder, "l", &packet(5002, 93000, DELTA)), Some((104, 13000)));
+        assert_eq!(forward(&mut forwarder, "h", &packet(103, 10000, DELTA)), None);
+    }
+
+    #[test]
+    fn layer_switch_wraps_around() {
+        let mut forwarder = forwarder("h", true);
+        assert_eq!(forward(&mut forwarder, "h", &packet(u16::MAX, u32::MAX - 1000, KEYFRAME)), Some((u16::MAX, u32::MAX - 1000)));
+
+        forwarder.target = "l".to_owned();
+        assert_eq!(forward(&mut forwarder, "l", &packet(10, 5, KEYFRAME)), Some((0, 1999)));
+        assert_eq!(forward(&mut forwarder, "l", &packet(11, 3005, DELTA)), Some((1, 4999)));
+    }
+
+    #[test]
+    fn audio_switches_at_any_packet() {
+        let mut forwarder = forwarder("a", false);
+        assert_eq!(forward(&mut forwarder, "a", &packet(10, 960, &[0xFC])), Some((10, 960)));
+
+        forwarder.target = "b".to_owned();
+        assert_eq!(forward(&mut forwarder, "b", &packet(300, 50000, &[0xFC])), Some((11, 2560)));
+    }
+
+    #[test]
+    fn keyframes_without_extensions() {
+        assert!(is_vp8_keyframe(&[0x10, 0x00]));
+        // A delta frame
+        assert!(!is_vp8_keyframe(&[0x10, 0x01]));
+        // Not the start of a partition, or not the first partition
+        assert!(!is_vp8_keyframe(&[0x00, 0x00]));
+        assert!(!is_vp8_keyframe(&[0x11, 0x00]));
+        assert!(!is_vp8_keyframe(&[]));
+        assert!(!is_vp8_keyframe(&[0x10]));
+    }
+
+    #[test]
+    fn keyframes_with_extensions() {
+        // The extension bytes are all odd, so reading one of them as the frame tag says delta
+        // 7 bit picture id
+        assert!(is_vp8_keyframe(&[0x90, 0x80, 0x05, 0x00]));
+        assert!(!is_vp8_keyframe(&[0x90, 0x80, 0x05, 0x01]));
+        // 15 bit picture id
+        assert!(is_vp8_keyframe(&[0x90, 0x80, 0x85, 0x05, 0x00]));
+        assert!(!is_vp8_keyframe(&[0x90, 0x80, 0x85, 0x05, 0x01]));
+        // TL0PICIDX
+        assert!(is_vp8_keyframe(&[0x90, 0x40, 0x01, 0x00]));
+        // TID and KEYIDX share a byte, with either or both present
+        assert!(is_vp8_keyframe(&[0x90, 0x20, 0x01, 0x00]));
+        assert!(is_vp8_keyframe(&[0x90, 0x10, 0x01, 0x00]));
+        assert!(is_vp8_keyframe(&[0x90, 0x30, 0x01, 0x00]));
+        // Everything
+        assert!(is_vp8_keyframe(&[0x90, 0xF0, 0x85, 0x05, 0x01, 0x01, 0x00]));
+        assert!(!is_vp8_keyframe(&[0x90, 0xF0, 0x85, 0x05, 0x01, 0x01, 0x01]));
+        // An extension byte with nothing in it
+        assert!(is_vp8_keyframe(&[0x90, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn truncated_extensions_are_not_keyframes() {
+        assert!(!is_vp8_keyframe(&[0x90]));
+        assert!(!is_vp8_keyframe(&[0x90, 0x80]));
+        assert!(!is_vp8_keyframe(&[0x90, 0x80, 0x85]));
+        assert!(!is_vp8_keyframe(&[0x90, 0x80, 0x85, 0x05]));
+        assert!(!is_vp8_keyframe(&[0x90, 0xF0, 0x85, 0x05, 0x01, 0x01]));
+    }
+}
@@ -0,0 +1,351 @@
+use anyhow::Result;
+use flume::{Receiver, Sender};
+use std::fmt::Debug;
+use std::sync::Arc;
+use crate::sfu::auth::{Claims, Permissions};
+use crate::sfu::egress::RtpEgress;
+use crate::sfu::media::PeerKind;
+use crate::sfu::server::Shutdown;
+use crate::sfu::signal::{self, SocketMessage, PROTOCOL_VERSION};
+use crate::PeerChanCommand;
+
+// The router's end of a client's signaling channel. Transports implement this to deliver
+// messages in their own wire format, over a websocket, a long-poll response or a gRPC stream.
+pub trait Signaler: Send + Sync + Debug {
+    fn send(&self, message: SocketMessage) -> Result<()>;
+}
+
+pub type SignalTx = Arc<dyn Signaler>;
+
+impl Signaler for Sender<SocketMessage> {
+    fn send(&self, message: SocketMessage) -> Result<()> {
+        Sender::send(self, message)?;
+        Ok(())
+    }
+}
+
+// A client connected over some transport. Messages from the client are read from rx and turned
+// into router commands by serve_connection, and the router answers on tx.
+#[derive(Debug)]
+pub struct Connection {
+    pub id: String,
+    pub tx: SignalTx,
+    pub rx: Receiver<SocketMessage>,
+    // Who the client proved to be, when authentication is turned on
+    pub identity: Option<Claims>,
+}
+
+// A way for clients to reach the SFU. Starting it hands back the connections it accepts. Some
+// transports, like WHIP, have no long-lived connection and send commands to the router directly.
+// Transports should stop accepting clients once shutdown starts.
+pub trait Transport: Send + 'static {
+    fn start(self: Box<Self>, peer_chan_tx: Sender<PeerChanCommand>, shutdown: Shutdown) -> Result<Receiver<Connection>>;
+}
+
+// Connects clients in the same process over channels, for tests and services embedding the SFU.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    connections: Receiver<Connection>,
+}
+
+// Opens connections to a ChannelTransport
+#[derive(Debug, Clone)]
+pub struct ChannelConnector {
+    connections: Sender<Connection>,
+}
+
+impl ChannelTransport {
+    pub fn new() -> (Self, ChannelConnector) {
+        let (tx, rx) = flume::unbounded::<Connection>();
+        (ChannelTransport { connections: rx }, ChannelConnector { connections: tx })
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn start(self: Box<Self>, _peer_chan_tx: Sender<PeerChanCommand>, _shutdown: Shutdown) -> Result<Receiver<Connection>> {
+        Ok(self.connections)
+    }
+}
+
+impl ChannelConnector {
+    // Connect a client, returning the channel to send its messages on and the channel the
+    // server's messages arrive on.
+    pub fn connect(&self, id: &str) -> Result<(Sender<SocketMessage>, Receiver<SocketMessage>)> {
+        let (in_tx, in_rx) = flume::unbounded::<SocketMessage>();
+        let (out_tx, out_rx) = flume::unbounded::<SocketMessage>();
+
+        self.connections.send(Connection {
+            id: id.to_owned(),
+            tx: Arc::new(out_tx),
+            rx: in_rx,
+            identity: None,
+        })?;
+
+        Ok((in_tx, out_rx))
+    }
+}
+
+// Handler to spin off for every new connection, whatever transport it came in on. Each
+// connection carries a single peer, identified by the server's id for the connection. Messages
+// naming any other peer are rejected, so a client can't act on someone else's connection.
+pub fn serve_connection(conn: Connection, peer_chan_tx: Sender<PeerChanCommand>) {
+    tokio::spawn(async move {
+        log::debug!("Handling a new connection {}.", conn.id);
+        let Connection { id, tx: socket_tx, rx: socket_rx, identity } = conn;
+        let permissions = identity.as_ref().map_or(Permissions::anonymous(), |c| c.permissions());
+        // The room and name the peer joined with, so its offers can be routed and the peer torn
+        // down when the connection closes
+        let mut joined: Option<(String, String)> = None;
+
+        // The router answers on a channel of its own, which is passed on to the client. That way
+        // the connection sees its room being closed, and forgets it joined so a later offer
+        // doesn't bring the room back.
+        let (router_tx, router_rx) = flume::unbounded::<SocketMessage>();
+        let router_tx: SignalTx = Arc::new(router_tx);
+
+        let _ = socket_tx.send(SocketMessage::Welcome {
+            uuid: id.to_owned(),
+            version: PROTOCOL_VERSION,
+        });
+
+        loop {
+            let signal = tokio::select! {
+                signal = socket_rx.recv_async() => match signal {
+                    Ok(signal) => signal,
+                    Err(_) => break,
+                },
+                Ok(message) = router_rx.recv_async() => {
+                    if let SocketMessage::RoomClosed { .. } = message {
+                        joined = None;
+                    }
+                    let _ = socket_tx.send(message);
+                    continue;
+                }
+            };
+
+            if let Some(claimed) = signal.uuid() {
+                if claimed != id {
+                    let _ = socket_tx.send(SocketMessage::error(format!("uuid {} doesn't belong to this connection", claimed)));
+                    continue;
+                }
+            }
+
+            match signal {
+                SocketMessage::Join { version, room, name, .. } => {
+                    if version != PROTOCOL_VERSION {
+                        let _ = socket_tx.send(SocketMessage::error(format!(
+                            "unsupported protocol version {}, expected {}", version, PROTOCOL_VERSION
+                        )));
+                        continue;
+                    }
+                    if joined.is_some() {
+                        let _ = socket_tx.send(SocketMessage::error("already joined"));
+                        continue;
+                    }
+                    let name = match &identity {
+                        Some(claims) if !permissions.can_join(claims, &room) => {
+                            let _ = socket_tx.send(SocketMessage::error(format!("not allowed in room {}", room)));
+                            continue;
+                        }
+                        Some(claims) => claims.display_name().to_owned(),
+                        None => name,
+                    };
+                    log::info!("Peer {:?} joining room {:?}", id, room);
+                    joined = Some((room, name));
+                },
+                SocketMessage::Offer { sdp, sources, .. } => {
+                    let (room, name) = match &joined {
+                        Some(joined) => joined.to_owned(),
+                        None => {
+                            let _ = socket_tx.send(SocketMessage::error(format!("offer for {} before join", id)));
+                            continue;
+                        }
+                    };
+                    log::debug!("Receiving offer: {:?}, for uuid: {:?} in room {:?}", sdp, id, room);
+                    peer_chan_tx.send(PeerChanCommand::ReceiveOffer {
+                        uuid: id.to_owned(),
+                        room,
+                        name,
+                        tx: Arc::clone(&router_tx),
+                        kind: PeerKind::Participant,
+                        permissions,
+                        sdp,
+                        sources
+                    }).unwrap();
+                },
+                SocketMessage::Answer { sdp, .. } => {
+                    log::debug!("Receiving answer: {:?}, for uuid: {:?}", sdp, id);
+                    peer_chan_tx.send(PeerChanCommand::ReceiveAnswer {
+                        uuid: id.to_owned(),
+                        sdp
+                    }).unwrap();
+                },
+                SocketMessage::Candidate { candidate, .. } => {
+                    // Candidates are held for the peer until its offer arrives, which only a
+                    // joined connection is cleaned up after
+                    if joined.is_none() {
+                        let _ = socket_tx.send(SocketMessage::error(format!("candidate for {} before join", id)));
+                        continue;
+                    }
+                    peer_chan_tx.send(PeerChanCommand::ReceiveIceCandidate {
+                        uuid: id.to_owned(),
+                        candidate
+                    }).unwrap();
+                },
+                SocketMessage::SelectLayer { publisher, track_id, layer, .. } => {
+                    peer_chan_tx.send(PeerChanCommand::SelectLayer {
+                        uuid: id.to_owned(),
+                        publisher,
+                        track_id,
+                        layer
+                    }).unwrap();
+                },
+                SocketMessage::CloseRoom { room } => {
+                    if !permissions.admin {
+                        let _ = socket_tx.send(SocketMessage::error("closing a room needs the admin permission"));
+                        continue;
+                    }
+                    peer_chan_tx.send(PeerChanCommand::CloseRoom { room }).unwrap();
+                },
+                SocketMessage::StartRecording { room } | SocketMessage::StopRecording { room } if !permissions.admin => {
+                    let _ = socket_tx.send(SocketMessage::error(format!("recording room {} needs the admin permission", room)));
+                },
+                SocketMessage::StartRecording { room } => {
+                    peer_chan_tx.send(PeerChanCommand::StartRecording { room }).unwrap();
+                },
+                SocketMessage::StopRecording { room } => {
+                    peer_chan_tx.send(PeerChanCommand::StopRecording { room }).unwrap();
+                },
+                SocketMessage::StartEgress { .. } | SocketMessage::StopEgress { .. } if !permissions.admin => {
+                    let _ = socket_tx.send(SocketMessage::error("RTP egress needs the admin permission"));
+                },
+                SocketMessage::StartEgress { room, publisher, track_id, dest, payload_type } => {
+                    let id = signal::uuid();
+                    let (done_tx, done_rx) = flume::bounded(1);
+                    peer_chan_tx.send(PeerChanCommand::StartEgress {
+                        id: id.to_owned(),
+                        // Clients get the SDP in the reply rather than writing files on the server
+                        egress: RtpEgress { room, publisher, track_id, dest, payload_type, sdp_path: None },
+                        done: done_tx,
+                    }).unwrap();
+
+                    // The router replies once the egress is sending
+                    let socket_tx = Arc::clone(&socket_tx);
+                    tokio::spawn(async move {
+                        let reply = match done_rx.recv_async().await {
+                            Ok(Ok(sdp)) => SocketMessage::EgressStarted { id, sdp },
+                            Ok(Err(err)) => SocketMessage::error(format!("couldn't start RTP egress: {}", err)),
+                            Err(_) => return,
+                        };
+                        let _ = socket_tx.send(reply);
+                    });
+                },
+                SocketMessage::StopEgress { id } => {
+                    peer_chan_tx.send(PeerChanCommand::StopEgress { id }).unwrap();
+                },
+                SocketMessage::Leave { .. } => {
+                    if joined.take().is_some() {
+                        peer_chan_tx.send(PeerChanCommand::PeerLeft { uuid: id.to_owned() }).unwrap();
+                    }
+                },
+                SocketMessage::Error { message } => {
+                    log::warn!("Client reported an error: {}", message);
+                }
+                _ => {
+                    let _ = socket_tx.send(SocketMessage::error("unexpected message from client"));
+                }
+            }
+        }
+
+        // The socket has closed
+        if joined.is_some() {
+            peer_chan_tx.send(PeerChanCommand::PeerLeft { uuid: id }).unwrap();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use webrtc::api::media_engine::MIME_TYPE_VP8;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+    use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+    use crate::sfu::api::prepare_local_api;
+    use crate::sfu::config::Config;
+    use crate::sfu::server::SfuServer;
+
+    async fn next_message(rx: &Receiver<SocketMessage>) -> SocketMessage {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv_async())
+            .await
+            .expect("timed out waiting for the server")
+            .expect("connection closed")
+    }
+
+    #[tokio::test]
+    async fn negotiates_over_channel_transport() {
+        // No STUN, so the test doesn't need the network
+        let config = Config { ice_servers: vec![], ..Config::default() };
+        let (transport, connector) = ChannelTransport::new();
+        let _server = SfuServer::builder(config.clone())
+            .serve_http(false)
+            .transport(transport)
+            .build()
+            .await
+            .unwrap();
+
+        let (tx, rx) = connector.connect("alice").unwrap();
+        match next_message(&rx).await {
+            SocketMessage::Welcome { uuid, version } => {
+                assert_eq!(uuid, "alice");
+                assert_eq!(version, PROTOCOL_VERSION);
+            },
+            message => panic!("expected a welcome, got {:?}", message),
+        }
+
+        tx.send(SocketMessage::Join {
+            version: PROTOCOL_VERSION,
+            uuid: "alice".to_owned(),
+            room: "lobby".to_owned(),
+            name: "Alice".to_owned(),
+        }).unwrap();
+
+        let pc = prepare_local_api(&config).unwrap()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability { mime_type: MIME_TYPE_VP8.to_owned(), ..Default::default() },
+            "camera".to_owned(),
+            "alice".to_owned(),
+        ));
+        pc.add_track(track).await.unwrap();
+        let offer = pc.create_offer(None).await.unwrap();
+        pc.set_local_description(offer.clone()).await.unwrap();
+
+        tx.send(SocketMessage::Offer {
+            uuid: "alice".to_owned(),
+            sdp: offer,
+            sources: HashMap::new(),
+        }).unwrap();
+
+        // Roster events and candidates may come first
+        let answer = loop {
+            match next_message(&rx).await {
+                SocketMessage::Answer { uuid, sdp } => {
+                    assert_eq!(uuid, "alice");
+                    break sdp;
+                },
+                SocketMessage::Error { message } => panic!("server rejected the offer: {}", message),
+                _ => continue,
+            }
+        };
+        assert_eq!(answer.sdp_type, RTCSdpType::Answer);
+        pc.set_remote_description(answer).await.unwrap();
+
+        pc.close().await.unwrap();
+    }
+}
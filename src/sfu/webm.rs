@@ -0,0 +1,581 @@
+use anyhow::Result;
+use std::io::{Seek, SeekFrom, Write};
+
+// Just enough of Matroska to write WebM files browsers play and seek in: a header declaring the
+// tracks, clusters of SimpleBlocks, and cues. Timestamps are in milliseconds.
+
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const VOID: u32 = 0xEC;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CUES: u32 = 0x1C53BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+// Room left at the start of the segment for the seek head, which can only be written once the
+// cues' position is known
+const SEEK_HEAD_SPACE: usize = 96;
+
+// Audio only files start a new cluster this often, video ones start one at every keyframe too
+const CLUSTER_DURATION: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebmTrack {
+    Vp8 { width: u16, height: u16 },
+    Opus { sample_rate: u32, channels: u16 },
+}
+
+impl WebmTrack {
+    pub fn is_video(&self) -> bool {
+        matches!(self, WebmTrack::Vp8 { .. })
+    }
+
+    fn entry(&self, number: u64) -> Vec<u8> {
+        let mut body = [
+            element(TRACK_NUMBER, &uint(number)),
+            element(TRACK_UID, &uint(number)),
+        ].concat();
+
+        match *self {
+            WebmTrack::Vp8 { width, height } => {
+                body.extend(element(TRACK_TYPE, &uint(1)));
+                body.extend(element(CODEC_ID, b"V_VP8"));
+                body.extend(element(VIDEO, &[
+                    element(PIXEL_WIDTH, &uint(width as u64)),
+                    element(PIXEL_HEIGHT, &uint(height as u64)),
+                ].concat()));
+            }
+            WebmTrack::Opus { sample_rate, channels } => {
+                body.extend(element(TRACK_TYPE, &uint(2)));
+                body.extend(element(CODEC_ID, b"A_OPUS"));
+                body.extend(element(CODEC_PRIVATE, &opus_head(sample_rate, channels)));
+                body.extend(element(AUDIO, &[
+                    element(SAMPLING_FREQUENCY, &(sample_rate as f64).to_be_bytes()),
+                    element(CHANNELS, &uint(channels as u64)),
+                ].concat()));
+            }
+        }
+
+        element(TRACK_ENTRY, &body)
+    }
+}
+
+pub struct WebmWriter<W: Write + Seek> {
+    out: W,
+    // Bytes written so far
+    position: u64,
+    // Where the segment's contents start, which positions in the seek head and cues count from
+    segment_start: u64,
+    seek_head_position: u64,
+    info_position: u64,
+    tracks_position: u64,
+    // Where the duration's value sits in the file, to fill in once it's known
+    duration_position: u64,
+    // The track cues point at, the video track if there is one
+    cue_track: u64,
+    video: bool,
+    // The cluster being built, with its timestamp
+    cluster: Option<(u64, Vec<u8>)>,
+    cues: Vec<u8>,
+    duration: u64,
+}
+
+impl<W: Write + Seek> WebmWriter<W> {
+    // Write the file's header, with tracks numbered from 1 in the order given
+    pub fn new(mut out: W, tracks: &[WebmTrack]) -> Result<Self> {
+        let header = element(EBML, &[
+            element(EBML_VERSION, &uint(1)),
+            element(EBML_READ_VERSION, &uint(1)),
+            element(EBML_MAX_ID_LENGTH, &uint(4)),
+            element(EBML_MAX_SIZE_LENGTH, &uint(8)),
+            element(DOC_TYPE, b"webm"),
+            element(DOC_TYPE_VERSION, &uint(4)),
+            element(DOC_TYPE_READ_VERSION, &uint(2)),
+        ].concat());
+
+        // The segment's size is filled in when the file is finished
+        let mut segment = id(SEGMENT);
+        segment.extend(fixed_size(0));
+
+        let seek_head = void(SEEK_HEAD_SPACE);
+
+        // Duration goes last, so its value is the last 8 bytes
+        let info = element(INFO, &[
+            element(TIMESTAMP_SCALE, &uint(1_000_000)),
+            element(MUXING_APP, b"sfu"),
+            element(WRITING_APP, b"sfu"),
+            element(DURATION, &0f64.to_be_bytes()),
+        ].concat());
+
+        let entries: Vec<u8> = tracks
+            .iter()
+            .enumerate()
+            .flat_map(|(i, track)| track.entry(i as u64 + 1))
+            .collect();
+        let tracks_element = element(TRACKS, &entries);
+
+        let segment_start = (header.len() + segment.len()) as u64;
+        let seek_head_position = segment_start;
+        let info_position = seek_head_position + seek_head.len() as u64;
+        let tracks_position = info_position + info.len() as u64;
+
+        for part in [&header, &segment, &seek_head, &info, &tracks_element] {
+            out.write_all(part)?;
+        }
+
+        Ok(WebmWriter {
+            out,
+            position: tracks_position + tracks_element.len() as u64,
+            segment_start,
+            seek_head_position,
+            info_position,
+            tracks_position,
+            duration_position: tracks_position - 8,
+            cue_track: tracks.iter().position(|t| t.is_video()).unwrap_or(0) as u64 + 1,
+            video: tracks.iter().any(|t| t.is_video()),
+            cluster: None,
+            cues: vec![],
+            duration: 0,
+        })
+    }
+
+    // Add a frame of a track, at a timestamp from the start of the file. Frames of each track
+    // must come in order, though tracks may be interleaved loosely.
+    pub fn write_frame(&mut self, track: u64, timestamp: u64, keyframe: bool, data: &[u8]) -> Result<()> {
+        let new_cluster = match &self.cluster {
+            None => true,
+            Some((start, _)) => {
+                (self.video && keyframe && track == self.cue_track)
+                    || timestamp.saturating_sub(*start) >= CLUSTER_DURATION
+                    || timestamp + (i16::MAX as u64) < *start
+            }
+        };
+        if new_cluster {
+            self.flush_cluster()?;
+            if keyframe && track == self.cue_track {
+                self.cues.extend(element(CUE_POINT, &[
+                    element(CUE_TIME, &uint(timestamp)),
+                    element(CUE_TRACK_POSITIONS, &[
+                        element(CUE_TRACK, &uint(track)),
+                        element(CUE_CLUSTER_POSITION, &uint(self.position - self.segment_start)),
+                    ].concat()),
+                ].concat()));
+            }
+            self.cluster = Some((timestamp, element(TIMESTAMP, &uint(timestamp))));
+        }
+
+        if let Some((start, blocks)) = &mut self.cluster {
+            let relative = (timestamp as i64 - *start as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+
+            let mut block = vint(track);
+            block.extend(relative.to_be_bytes());
+            block.push(if keyframe { 0x80 } else { 0 });
+            block.extend(data);
+            blocks.extend(element(SIMPLE_BLOCK, &block));
+        }
+
+        self.duration = self.duration.max(timestamp);
+        Ok(())
+    }
+
+    // Write out the last cluster and the cues, and fill in the sizes and positions left blank
+    pub fn finish(mut self) -> Result<W> {
+        self.flush_cluster()?;
+
+        let cues_position = self.position;
+        let cues = element(CUES, &self.cues);
+        self.out.write_all(&cues)?;
+        self.position += cues.len() as u64;
+
+        let mut seek_head = element(SEEK_HEAD, &[
+            seek(INFO, self.info_position - self.segment_start),
+            seek(TRACKS, self.tracks_position - self.segment_start),
+            seek(CUES, cues_position - self.segment_start),
+        ].concat());
+        seek_head.extend(void(SEEK_HEAD_SPACE - seek_head.len()));
+
+        self.out.seek(SeekFrom::Start(self.segment_start - 8))?;
+        self.out.write_all(&fixed_size(self.position - self.segment_start))?;
+        self.out.seek(SeekFrom::Start(self.seek_head_position))?;
+        self.out.write_all(&seek_head)?;
+        self.out.seek(SeekFrom::Start(self.duration_position))?;
+        self.out.write_all(&(self.duration as f64).to_be_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+
+    fn flush_cluster(&mut self) -> Result<()> {
+        if let Some((_, blocks)) = self.cluster.take() {
+            let cluster = element(CLUSTER, &blocks);
+            self.out.write_all(&cluster)?;
+            self.position += cluster.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+// The Opus identification header, which Matroska carries as the track's codec private data
+fn opus_head(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(channels as u8);
+    head.extend(0u16.to_le_bytes());
+    head.extend(sample_rate.to_le_bytes());
+    head.extend(0u16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+fn seek(target: u32, position: u64) -> Vec<u8> {
+    // A fixed width position keeps the seek head's size the same whatever the positions are
+    let mut position_element = id(SEEK_POSITION);
+    position_element.extend(vint(8));
+    position_element.extend(position.to_be_bytes());
+
+    element(SEEK, &[element(SEEK_ID, &id(target)), position_element].concat())
+}
+
+fn element(element_id: u32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = id(element_id);
+    bytes.extend(vint(body.len() as u64));
+    bytes.extend(body);
+    bytes
+}
+
+// Padding that readers skip over, taking up exactly len bytes
+fn void(len: usize) -> Vec<u8> {
+    let mut bytes = id(VOID);
+    let size = if len - 2 < 0x7F { vint((len - 2) as u64) } else { fixed_size((len - 9) as u64).to_vec() };
+    bytes.extend(size);
+    bytes.resize(len, 0);
+    bytes
+}
+
+// Element ids are written with their length marker already in place
+fn id(element_id: u32) -> Vec<u8> {
+    let bytes = element_id.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn uint(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    bytes[skip..].to_vec()
+}
+
+// A variable length integer, in as few bytes as will hold it
+fn vint(value: u64) -> Vec<u8> {
+    let len = (1..8).find(|len| value < (1 << (7 * len)) - 1).unwrap_or(8);
+    let bytes = (value | (1 << (7 * len))).to_be_bytes();
+    bytes[8 - len..].to_vec()
+}
+
+// A size taking the full 8 bytes, for sizes patched in later
+fn fixed_size(value: u64) -> [u8; 8] {
+    (value | (1 << 56)).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Ids are read with their length marker, as they're written
+    fn read_id(bytes: &[u8]) -> (u32, usize) {
+        let len = bytes[0].leading_zeros() as usize + 1;
+        (bytes[..len].iter().fold(0, |id, b| (id << 8) | *b as u32), len)
+    }
+
+    fn read_vint(bytes: &[u8]) -> (u64, usize) {
+        let len = bytes[0].leading_zeros() as usize + 1;
+        let value = bytes[..len].iter().fold(0, |value, b| (value << 8) | *b as u64);
+        (value & !(1 << (7 * len)), len)
+    }
+
+    fn read_uint(body: &[u8]) -> u64 {
+        body.iter().fold(0, |value, b| (value << 8) | *b as u64)
+    }
+
+    // The elements one after another in a body, with where each starts and its own body
+    fn children(body: &[u8]) -> Vec<(usize, u32, &[u8])> {
+        let mut elements = vec![];
+        let mut offset = 0;
+        while offset < body.len() {
+            let (element_id, id_len) = read_id(&body[offset..]);
+            let (size, size_len) = read_vint(&body[offset + id_len..]);
+            let start = offset + id_len + size_len;
+            let end = start + size as usize;
+            elements.push((offset, element_id, &body[start..end]));
+            offset = end;
+        }
+        elements
+    }
+
+    fn ids(elements: &[(usize, u32, &[u8])]) -> Vec<u32> {
+        elements.iter().map(|(_, element_id, _)| *element_id).collect()
+    }
+
+    // The body of the only child with an id
+    fn child<'a>(body: &'a [u8], element_id: u32) -> &'a [u8] {
+        let found: Vec<&[u8]> = children(body)
+            .into_iter()
+            .filter(|(_, found, _)| *found == element_id)
+            .map(|(_, _, body)| body)
+            .collect();
+        assert_eq!(found.len(), 1, "expected one element {:X}", element_id);
+        found[0]
+    }
+
+    // A cluster's timestamp, and its blocks' tracks, relative timestamps, keyframe flags and data
+    fn cluster_blocks(cluster: &[u8]) -> (u64, Vec<(u64, i16, bool, Vec<u8>)>) {
+        let elements = children(cluster);
+        assert_eq!(elements[0].1, TIMESTAMP);
+
+        let blocks = elements[1..]
+            .iter()
+            .map(|(_, element_id, block)| {
+                assert_eq!(*element_id, SIMPLE_BLOCK);
+                let (track, len) = read_vint(block);
+                let relative = i16::from_be_bytes([block[len], block[len + 1]]);
+                (track, relative, block[len + 2] & 0x80 != 0, block[len + 3..].to_vec())
+            })
+            .collect();
+
+        (read_uint(elements[0].2), blocks)
+    }
+
+    // Write a file of one byte frames
+    fn write(tracks: &[WebmTrack], frames: &[(u64, u64, bool, u8)]) -> Vec<u8> {
+        let mut writer = WebmWriter::new(Cursor::new(vec![]), tracks).unwrap();
+        for (track, timestamp, keyframe, data) in frames {
+            writer.write_frame(*track, *timestamp, *keyframe, &[*data]).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    // The segment's body, checking the file is just the EBML header and a segment sized to the
+    // end of the file
+    fn segment(file: &[u8]) -> &[u8] {
+        let top = children(file);
+        assert_eq!(ids(&top), vec![EBML, SEGMENT]);
+        assert_eq!(child(top[0].2, DOC_TYPE), b"webm");
+        top[1].2
+    }
+
+    #[test]
+    fn vints_take_as_few_bytes_as_hold_them() {
+        assert_eq!(vint(0), vec![0x80]);
+        assert_eq!(vint(1), vec![0x81]);
+        assert_eq!(vint(126), vec![0xFE]);
+        // All ones is reserved, so 127 needs a second byte
+        assert_eq!(vint(127), vec![0x40, 0x7F]);
+        assert_eq!(vint(16382), vec![0x7F, 0xFE]);
+        assert_eq!(vint(16383), vec![0x20, 0x3F, 0xFF]);
+
+        for value in [0, 126, 127, 16383, 1 << 40] {
+            assert_eq!(read_vint(&vint(value)), (value, vint(value).len()));
+        }
+        assert_eq!(fixed_size(5), [0x01, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(read_vint(&fixed_size(5)), (5, 8));
+    }
+
+    #[test]
+    fn ids_and_uints_drop_leading_zeros() {
+        assert_eq!(id(EBML), vec![0x1A, 0x45, 0xDF, 0xA3]);
+        assert_eq!(id(SEEK), vec![0x4D, 0xBB]);
+        assert_eq!(id(VOID), vec![0xEC]);
+        assert_eq!(uint(0), vec![0]);
+        assert_eq!(uint(255), vec![0xFF]);
+        assert_eq!(uint(256), vec![0x01, 0x00]);
+        assert_eq!(uint(1_000_000), vec![0x0F, 0x42, 0x40]);
+    }
+
+    #[test]
+    fn voids_take_up_exactly_their_length() {
+        // Up to 128 bytes the size fits in one byte, past that it takes eight
+        for len in [2, 3, 96, 128, 129, 200] {
+            let padding = void(len);
+            assert_eq!(padding.len(), len);
+
+            let elements = children(&padding);
+            assert_eq!(ids(&elements), vec![VOID]);
+            assert_eq!(elements[0].2.len(), len - if len <= 128 { 2 } else { 9 });
+        }
+    }
+
+    #[test]
+    fn seek_head_fits_its_space() {
+        let seek_head = element(SEEK_HEAD, &[seek(INFO, 0), seek(TRACKS, 0), seek(CUES, u64::MAX)].concat());
+        assert_eq!(seek_head.len(), 68);
+        assert!(seek_head.len() + 2 <= SEEK_HEAD_SPACE);
+    }
+
+    #[test]
+    fn two_track_file() {
+        let tracks = [
+            WebmTrack::Vp8 { width: 320, height: 240 },
+            WebmTrack::Opus { sample_rate: 48000, channels: 2 },
+        ];
+        let file = write(&tracks, &[
+            (1, 0, true, 1),
+            (2, 20, true, 2),
+            (1, 33, false, 3),
+            (2, 40, true, 4),
+            // A keyframe starts a new cluster, and audio a little behind it goes in that cluster
+            (1, 1000, true, 5),
+            (2, 990, true, 6),
+            (2, 1010, true, 7),
+        ]);
+
+        let segment = segment(&file);
+        let elements = children(segment);
+        assert_eq!(ids(&elements), vec![SEEK_HEAD, VOID, INFO, TRACKS, CLUSTER, CLUSTER, CUES]);
+
+        // The seek head and the void after it fill the space left for them exactly
+        assert_eq!(elements[0].0, 0);
+        assert_eq!(elements[1].0, 68);
+        assert_eq!(elements[2].0, SEEK_HEAD_SPACE);
+
+        // Seek positions count from the start of the segment's body
+        let targets: Vec<(u32, u64)> = children(elements[0].2)
+            .into_iter()
+            .map(|(_, element_id, body)| {
+                assert_eq!(element_id, SEEK);
+                (read_id(child(body, SEEK_ID)).0, read_uint(child(body, SEEK_POSITION)))
+            })
+            .collect();
+        assert_eq!(targets, vec![
+            (INFO, elements[2].0 as u64),
+            (TRACKS, elements[3].0 as u64),
+            (CUES, elements[6].0 as u64),
+        ]);
+
+        let info = elements[2].2;
+        assert_eq!(read_uint(child(info, TIMESTAMP_SCALE)), 1_000_000);
+        let duration: [u8; 8] = child(info, DURATION).try_into().unwrap();
+        assert_eq!(f64::from_be_bytes(duration), 1010.0);
+
+        let entries = children(elements[3].2);
+        assert_eq!(ids(&entries), vec![TRACK_ENTRY, TRACK_ENTRY]);
+        assert_eq!(read_uint(child(entries[0].2, TRACK_NUMBER)), 1);
+        assert_eq!(child(entries[0].2, CODEC_ID), b"V_VP8");
+        assert_eq!(read_uint(child(child(entries[0].2, VIDEO), PIXEL_WIDTH)), 320);
+        assert_eq!(read_uint(child(child(entries[0].2, VIDEO), PIXEL_HEIGHT)), 240);
+        assert_eq!(read_uint(child(entries[1].2, TRACK_NUMBER)), 2);
+        assert_eq!(child(entries[1].2, CODEC_ID), b"A_OPUS");
+        assert_eq!(child(entries[1].2, CODEC_PRIVATE), opus_head(48000, 2).as_slice());
+        assert_eq!(read_uint(child(child(entries[1].2, AUDIO), CHANNELS)), 2);
+
+        assert_eq!(cluster_blocks(elements[4].2), (0, vec![
+            (1, 0, true, vec![1]),
+            (2, 20, true, vec![2]),
+            (1, 33, false, vec![3]),
+            (2, 40, true, vec![4]),
+        ]));
+        assert_eq!(cluster_blocks(elements[5].2), (1000, vec![
+            (1, 0, true, vec![5]),
+            (2, -10, true, vec![6]),
+            (2, 10, true, vec![7]),
+        ]));
+
+        // A cue for each video keyframe, pointing at the cluster it starts
+        let cues: Vec<(u64, u64, u64)> = children(elements[6].2)
+            .into_iter()
+            .map(|(_, element_id, body)| {
+                assert_eq!(element_id, CUE_POINT);
+                let positions = child(body, CUE_TRACK_POSITIONS);
+                (
+                    read_uint(child(body, CUE_TIME)),
+                    read_uint(child(positions, CUE_TRACK)),
+                    read_uint(child(positions, CUE_CLUSTER_POSITION)),
+                )
+            })
+            .collect();
+        assert_eq!(cues, vec![(0, 1, elements[4].0 as u64), (1000, 1, elements[5].0 as u64)]);
+    }
+
+    #[test]
+    fn audio_only_clusters_are_split_by_duration() {
+        let file = write(&[WebmTrack::Opus { sample_rate: 48000, channels: 1 }], &[
+            (1, 0, true, 0),
+            (1, CLUSTER_DURATION - 20, true, 1),
+            (1, CLUSTER_DURATION, true, 2),
+            (1, 2 * CLUSTER_DURATION - 1, true, 3),
+            (1, 2 * CLUSTER_DURATION, true, 4),
+        ]);
+
+        let elements = children(segment(&file));
+        let clusters: Vec<u64> = elements
+            .iter()
+            .filter(|(_, element_id, _)| *element_id == CLUSTER)
+            .map(|(_, _, body)| cluster_blocks(body).0)
+            .collect();
+        assert_eq!(clusters, vec![0, CLUSTER_DURATION, 2 * CLUSTER_DURATION]);
+
+        // Each cluster of an audio only file gets a cue
+        let cues = elements.iter().find(|(_, element_id, _)| *element_id == CUES).unwrap().2;
+        assert_eq!(children(cues).len(), 3);
+    }
+
+    #[test]
+    fn frames_too_far_behind_the_cluster_start_a_new_one() {
+        let tracks = [
+            WebmTrack::Vp8 { width: 640, height: 480 },
+            WebmTrack::Opus { sample_rate: 48000, channels: 2 },
+        ];
+        let start = 40000;
+        let file = write(&tracks, &[
+            (1, start, true, 0),
+            // As far behind as a block's relative timestamp reaches
+            (2, start - i16::MAX as u64, true, 1),
+            (2, start - i16::MAX as u64 - 1, true, 2),
+        ]);
+
+        let clusters: Vec<(u64, Vec<(u64, i16, bool, Vec<u8>)>)> = children(segment(&file))
+            .into_iter()
+            .filter(|(_, element_id, _)| *element_id == CLUSTER)
+            .map(|(_, _, body)| cluster_blocks(body))
+            .collect();
+        assert_eq!(clusters, vec![
+            (start, vec![(1, 0, true, vec![0]), (2, -i16::MAX, true, vec![1])]),
+            (start - i16::MAX as u64 - 1, vec![(2, 0, true, vec![2])]),
+        ]);
+    }
+}
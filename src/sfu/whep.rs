@@ -0,0 +1,60 @@
+use anyhow::Result;
+use flume::Sender;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use crate::sfu::auth::Claims;
+use crate::sfu::media::PeerKind;
+use crate::sfu::whip::{authorize, create_session, end_session, status, trickle, Sessions};
+use crate::PeerChanCommand;
+
+// WHEP (WebRTC-HTTP Egress Protocol) lets players watch a room without joining it over the
+// websocket. The viewer offers receive-only transceivers and is answered with the room's tracks:
+//
+//   POST   /whep/<room>               watch every publisher in the room
+//   POST   /whep/<room>/<publisher>   watch a single publisher
+//   PATCH  /whep/<room>/<uuid>        trickle ICE candidates as an SDP fragment
+//   DELETE /whep/<room>/<uuid>        end the session
+pub const WHEP_PATH: &str = "/whep";
+
+lazy_static! {
+    static ref SESSIONS: Sessions = Sessions::default();
+}
+
+/// Handle a request under the WHEP path.
+pub async fn handle_whep(
+    request: Request<Body>,
+    uuid: String,
+    peer_chan_tx: Sender<PeerChanCommand>,
+    identity: Option<Claims>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let path = request.uri().path()[WHEP_PATH.len()..].trim_matches('/').to_owned();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let permissions = match segments.first() {
+        Some(room) => authorize(identity.as_ref(), room, |p| p.subscribe),
+        None => None,
+    };
+
+    match (request.method(), segments.as_slice(), permissions) {
+        (&Method::POST, [room], Some(permissions)) => {
+            let room = room.to_string();
+            let kind = PeerKind::Viewer { publisher: None };
+            create_session(request, uuid, room, kind, permissions, WHEP_PATH, &SESSIONS, peer_chan_tx).await
+        }
+        (&Method::POST, [room, publisher], Some(permissions)) => {
+            let room = room.to_string();
+            let kind = PeerKind::Viewer { publisher: Some(publisher.to_string()) };
+            create_session(request, uuid, room, kind, permissions, WHEP_PATH, &SESSIONS, peer_chan_tx).await
+        }
+        (&Method::POST, [_] | [_, _], None) => status(StatusCode::FORBIDDEN),
+        (&Method::PATCH, [room, id], _) if SESSIONS.contains(room, id) => {
+            let id = id.to_string();
+            trickle(request, id, peer_chan_tx).await
+        }
+        (&Method::DELETE, [room, id], _) if SESSIONS.contains(room, id) => {
+            end_session(id, &SESSIONS, peer_chan_tx)
+        }
+        (&Method::PATCH, [_, _], _) | (&Method::DELETE, [_, _], _) => status(StatusCode::NOT_FOUND),
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
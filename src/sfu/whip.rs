@@ -0,0 +1,275 @@
+use anyhow::Result;
+use flume::Sender;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use crate::sfu::auth::{Claims, Permissions};
+use crate::sfu::media::PeerKind;
+use crate::sfu::signal::SocketMessage;
+use crate::PeerChanCommand;
+
+// WHIP (WebRTC-HTTP Ingestion Protocol) lets encoders like OBS or GStreamer's whipsink publish
+// into a room with plain HTTP requests:
+//
+//   POST   /whip/<room>          SDP offer in, 201 with the SDP answer and the session Location
+//   PATCH  /whip/<room>/<uuid>   trickle ICE candidates as an SDP fragment
+//   DELETE /whip/<room>/<uuid>   end the session
+pub const WHIP_PATH: &str = "/whip";
+
+// Whether a request path is the base path or below it, so /whip/lobby matches /whip and
+// /whipped doesn't
+pub fn is_under(path: &str, base: &str) -> bool {
+    path.strip_prefix(base).map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+const SDP_FRAG_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+
+// How long to wait on the router for an answer before giving up on an offer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Peers created over HTTP, mapping each peer uuid to its room
+#[derive(Debug, Default)]
+pub struct Sessions(Mutex<HashMap<String, String>>);
+
+impl Sessions {
+    pub fn insert(&self, uuid: &str, room: &str) {
+        self.0.lock().unwrap().insert(uuid.to_owned(), room.to_owned());
+    }
+
+    pub fn remove(&self, uuid: &str) {
+        self.0.lock().unwrap().remove(uuid);
+    }
+
+    pub fn contains(&self, room: &str, uuid: &str) -> bool {
+        self.0.lock().unwrap().get(uuid).map_or(false, |r| r == room)
+    }
+}
+
+lazy_static! {
+    static ref SESSIONS: Sessions = Sessions::default();
+}
+
+/// Handle a request under the WHIP path.
+pub async fn handle_whip(
+    request: Request<Body>,
+    uuid: String,
+    peer_chan_tx: Sender<PeerChanCommand>,
+    identity: Option<Claims>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let path = request.uri().path()[WHIP_PATH.len()..].trim_matches('/').to_owned();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (request.method(), segments.as_slice()) {
+        (&Method::POST, [room]) => {
+            let permissions = match authorize(identity.as_ref(), room, |p| p.publish) {
+                Some(permissions) => permissions,
+                None => return status(StatusCode::FORBIDDEN),
+            };
+            let room = room.to_string();
+            create_session(request, uuid, room, PeerKind::Ingest, permissions, WHIP_PATH, &SESSIONS, peer_chan_tx).await
+        }
+        (&Method::PATCH, [room, id]) if SESSIONS.contains(room, id) => {
+            let id = id.to_string();
+            trickle(request, id, peer_chan_tx).await
+        }
+        (&Method::DELETE, [room, id]) if SESSIONS.contains(room, id) => {
+            end_session(id, &SESSIONS, peer_chan_tx)
+        }
+        (&Method::PATCH, [_, _]) | (&Method::DELETE, [_, _]) => status(StatusCode::NOT_FOUND),
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+// Create a peer from the offer and respond with its answer, and the session's Location under
+// the given base path.
+pub async fn create_session(
+    request: Request<Body>,
+    uuid: String,
+    room: String,
+    kind: PeerKind,
+    permissions: Permissions,
+    base_path: &str,
+    sessions: &'static Sessions,
+    peer_chan_tx: Sender<PeerChanCommand>,
+) -> Result<Response<Body>, anyhow::Error> {
+    if !has_content_type(&request, SDP_CONTENT_TYPE) {
+        return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+    let offer = match RTCSessionDescription::offer(String::from_utf8_lossy(&body).into_owned()) {
+        Ok(offer) => offer,
+        Err(e) => return text(StatusCode::BAD_REQUEST, format!("invalid offer: {}", e)),
+    };
+
+    // The router answers on this channel, like it would on a websocket
+    let (tx, rx) = flume::unbounded::<SocketMessage>();
+
+    log::info!("Receiving {:?} offer for uuid: {:?} in room {:?}", kind, uuid, room);
+    peer_chan_tx.send(PeerChanCommand::ReceiveOffer {
+        uuid: uuid.to_owned(),
+        room: room.to_owned(),
+        name: String::new(),
+        sdp: offer,
+        sources: HashMap::new(),
+        tx: Arc::new(tx),
+        kind,
+        permissions,
+    })?;
+
+    let answer = tokio::time::timeout(ANSWER_TIMEOUT, rx.recv_async()).await;
+    match answer {
+        Ok(Ok(SocketMessage::Answer { sdp, .. })) => {
+            sessions.insert(&uuid, &room);
+
+            // The router drops its end of the channel once the peer is gone, whether the session
+            // was deleted, its connection failed, its room was closed or the server drained
+            let session = uuid.to_owned();
+            tokio::spawn(async move {
+                while rx.recv_async().await.is_ok() {}
+                sessions.remove(&session);
+            });
+
+            Ok(Response::builder()
+                .status(StatusCode::CREATED)
+                .header(CONTENT_TYPE, SDP_CONTENT_TYPE)
+                .header(LOCATION, format!("{}/{}/{}", base_path, room, uuid))
+                .body(Body::from(sdp.sdp))?)
+        }
+        Ok(Ok(SocketMessage::Error { message })) => text(StatusCode::BAD_REQUEST, message),
+        _ => {
+            peer_chan_tx.send(PeerChanCommand::PeerLeft { uuid })?;
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// The permissions a session gets, if the token allows it into the room with the permission the
+// endpoint needs. Anyone may publish and watch when authentication is turned off.
+pub fn authorize(identity: Option<&Claims>, room: &str, needs: fn(&Permissions) -> bool) -> Option<Permissions> {
+    match identity {
+        None => Some(Permissions::anonymous()),
+        Some(claims) => {
+            let permissions = claims.permissions();
+            if permissions.can_join(claims, room) && needs(&permissions) {
+                Some(permissions)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pub fn end_session(
+    uuid: &str,
+    sessions: &Sessions,
+    peer_chan_tx: Sender<PeerChanCommand>,
+) -> Result<Response<Body>, anyhow::Error> {
+    sessions.remove(uuid);
+    peer_chan_tx.send(PeerChanCommand::PeerLeft { uuid: uuid.to_owned() })?;
+    status(StatusCode::OK)
+}
+
+// Pass trickled candidates from an SDP fragment on to the peer.
+pub async fn trickle(
+    request: Request<Body>,
+    uuid: String,
+    peer_chan_tx: Sender<PeerChanCommand>,
+) -> Result<Response<Body>, anyhow::Error> {
+    if !has_content_type(&request, SDP_FRAG_CONTENT_TYPE) {
+        return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+
+    for candidate in parse_sdp_fragment(&String::from_utf8_lossy(&body)) {
+        peer_chan_tx.send(PeerChanCommand::ReceiveIceCandidate {
+            uuid: uuid.to_owned(),
+            candidate,
+        })?;
+    }
+
+    status(StatusCode::NO_CONTENT)
+}
+
+// Pull the candidates out of a trickle-ice-sdpfrag body, tagging each with the mid it follows.
+fn parse_sdp_fragment(fragment: &str) -> Vec<RTCIceCandidateInit> {
+    let mut candidates = vec![];
+    let mut mid: Option<String> = None;
+    let mut ufrag: Option<String> = None;
+
+    for line in fragment.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("a=mid:") {
+            mid = Some(value.to_owned());
+        } else if let Some(value) = line.strip_prefix("a=ice-ufrag:") {
+            ufrag = Some(value.to_owned());
+        } else if let Some(value) = line.strip_prefix("a=") {
+            if value.starts_with("candidate:") {
+                candidates.push(RTCIceCandidateInit {
+                    candidate: value.to_owned(),
+                    sdp_mid: mid.clone(),
+                    username_fragment: ufrag.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    candidates
+}
+
+fn has_content_type(request: &Request<Body>, content_type: &str) -> bool {
+    request.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with(content_type))
+}
+
+pub fn status(status: StatusCode) -> Result<Response<Body>, anyhow::Error> {
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
+pub fn text(status: StatusCode, message: String) -> Result<Response<Body>, anyhow::Error> {
+    Ok(Response::builder().status(status).body(Body::from(message))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_whole_path_segments() {
+        assert!(is_under("/whip", WHIP_PATH));
+        assert!(is_under("/whip/", WHIP_PATH));
+        assert!(is_under("/whip/lobby/1234", WHIP_PATH));
+        assert!(!is_under("/whipped", WHIP_PATH));
+        assert!(!is_under("/wh", WHIP_PATH));
+    }
+
+    #[test]
+    fn parses_candidates_from_sdp_fragment() {
+        let fragment = "a=ice-ufrag:EsAw\r\n\
+                        a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+                        m=audio 9 RTP/AVP 0\r\n\
+                        a=mid:0\r\n\
+                        a=candidate:1 1 UDP 2130706431 198.51.100.1 39132 typ host\r\n\
+                        m=video 9 RTP/AVP 96\r\n\
+                        a=mid:1\r\n\
+                        a=candidate:2 1 UDP 1694498815 192.0.2.3 45664 typ srflx raddr 0.0.0.0 rport 0\r\n\
+                        a=end-of-candidates\r\n";
+
+        let candidates = parse_sdp_fragment(fragment);
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].candidate, "candidate:1 1 UDP 2130706431 198.51.100.1 39132 typ host");
+        assert_eq!(candidates[0].sdp_mid.as_deref(), Some("0"));
+        assert_eq!(candidates[0].username_fragment.as_deref(), Some("EsAw"));
+        assert_eq!(candidates[1].sdp_mid.as_deref(), Some("1"));
+        assert!(candidates[1].candidate.ends_with("typ srflx raddr 0.0.0.0 rport 0"));
+    }
+}